@@ -0,0 +1,177 @@
+// The applet's commands. They access the memory only through `Memory`, so
+// besides being part of the applet, this file is compiled into the uploader.
+// There, the simulated SAM-BA monitor runs the commands against its simulated
+// memory, which means the tests exercise the same code that runs on the
+// device (see `uploader/src/simulator.rs`). This is also why nothing from
+// `core` or `std` may be used here.
+//
+// See `uploader/src/applet.rs` for the uploader's side and the description of
+// the commands.
+
+
+/// Access to the memory, including the registers of the flash controllers.
+pub trait Memory {
+    fn read(&mut self, address: u32) -> u32;
+    fn write(&mut self, address: u32, value: u32);
+    fn read_byte(&mut self, address: u32) -> u8;
+}
+
+
+// Layout of the mailbox, through which the uploader passes a command and its
+// arguments, and the applet passes back the status and the result.
+pub const MAILBOX_ADDR: u32 = 0x20071000;
+
+pub const COMMAND: u32 = 0x00;
+pub const STATUS : u32 = 0x04;
+pub const RESULT : u32 = 0x08;
+pub const ARGS   : u32 = 0x0c;
+
+pub const WRITE_PAGES: u32 = 1;
+pub const ERASE_ALL  : u32 = 2;
+pub const CRC32_PAGES: u32 = 3;
+pub const CRC32      : u32 = 4;
+
+pub const STATUS_OK     : u32 = 0x00000000;
+pub const STATUS_UNKNOWN: u32 = 0xffffffff;
+
+// Registers of the flash controller, relative to its base address. See data
+// sheet, section 18.5.
+const EEFC_FCR: u32 = 0x04;
+const EEFC_FSR: u32 = 0x08;
+
+const FCR_KEY: u32 = 0x5a << 24;
+
+const FSR_FRDY  : u32 = 0x1;
+const FSR_FCMDE : u32 = 0x2;
+const FSR_FLOCKE: u32 = 0x4;
+
+// Flash commands. See data sheet, section 18.4.3.
+const ERASE_PAGE_AND_WRITE_PAGE: u32 = 0x03;
+const ERASE_ALL_COMMAND        : u32 = 0x05;
+
+
+/// Executes the command in the mailbox and writes back its status and result.
+pub fn execute<M: Memory>(memory: &mut M) {
+    let mut args = [0; 6];
+    for (i, arg) in args.iter_mut().enumerate() {
+        *arg = memory.read(MAILBOX_ADDR + ARGS + i as u32 * 4);
+    }
+
+    let (status, result) = match memory.read(MAILBOX_ADDR + COMMAND) {
+        WRITE_PAGES =>
+            write_pages(
+                memory, args[0], args[1], args[2], args[3], args[4], args[5],
+            ),
+        ERASE_ALL =>
+            (flash_command(memory, args[0], ERASE_ALL_COMMAND, 0), 0),
+        CRC32_PAGES =>
+            crc32_pages(memory, args[0], args[1], args[2], args[3]),
+        CRC32 =>
+            (STATUS_OK, crc32(memory, args[0], args[1])),
+        _ =>
+            (STATUS_UNKNOWN, 0),
+    };
+
+    // The uploader waits for the status to change, so it goes last.
+    memory.write(MAILBOX_ADDR + RESULT, result);
+    memory.write(MAILBOX_ADDR + STATUS, status);
+}
+
+
+/// Writes consecutive pages from the buffer. Returns the index of the page
+/// that failed, if any.
+fn write_pages<M: Memory>(
+    memory     : &mut M,
+    eefc       : u32,
+    address    : u32,
+    page_number: u32,
+    count      : u32,
+    buffer     : u32,
+    page_size  : u32,
+)
+    -> (u32, u32)
+{
+    for i in 0 .. count {
+        // Writing to the flash memory fills the controller's latch buffer.
+        // The data is only programmed by the command below.
+        for word in 0 .. page_size / 4 {
+            let offset = i * page_size + word * 4;
+            let value  = memory.read(buffer + offset);
+            memory.write(address + offset, value);
+        }
+
+        let status = flash_command(
+            memory,
+            eefc,
+            ERASE_PAGE_AND_WRITE_PAGE,
+            page_number + i,
+        );
+        if status != STATUS_OK {
+            return (status, i);
+        }
+    }
+
+    (STATUS_OK, 0)
+}
+
+/// Executes a flash command and waits for it to finish. Returns the status
+/// register, if the command failed.
+fn flash_command<M: Memory>(
+    memory  : &mut M,
+    eefc    : u32,
+    command : u32,
+    argument: u32,
+)
+    -> u32
+{
+    memory.write(eefc + EEFC_FCR, FCR_KEY | argument << 8 | command);
+
+    // The error flags are cleared by reading the status register, so they need
+    // to be collected while waiting.
+    let mut status = 0;
+    while status & FSR_FRDY == 0 {
+        status |= memory.read(eefc + EEFC_FSR);
+    }
+
+    if status & (FSR_FCMDE | FSR_FLOCKE) != 0 {
+        status
+    }
+    else {
+        STATUS_OK
+    }
+}
+
+/// Writes the CRC32 of each page to consecutive words at `destination`.
+fn crc32_pages<M: Memory>(
+    memory     : &mut M,
+    address    : u32,
+    count      : u32,
+    page_size  : u32,
+    destination: u32,
+)
+    -> (u32, u32)
+{
+    for i in 0 .. count {
+        let crc = crc32(memory, address + i * page_size, page_size);
+        memory.write(destination + i * 4, crc);
+    }
+
+    (STATUS_OK, 0)
+}
+
+/// Computes the CRC32 of a range of memory. Uses the same algorithm as
+/// `crc32` in `uploader/src/applet.rs`, without a table to keep the applet
+/// small.
+fn crc32<M: Memory>(memory: &mut M, address: u32, length: u32) -> u32 {
+    let mut crc = 0xffffffff;
+
+    for offset in 0 .. length {
+        crc ^= memory.read_byte(address + offset) as u32;
+
+        for _ in 0 .. 8 {
+            crc = if crc & 1 != 0 { crc >> 1 ^ 0xedb88320 } else { crc >> 1 };
+        }
+    }
+
+    !crc
+}
@@ -0,0 +1,65 @@
+// Applet that programs the flash memory on behalf of the uploader. It is loaded
+// into SRAM and started through the SAM-BA monitor, executes one command from
+// the mailbox, and returns to the monitor. The commands are in `commands.rs`.
+// See `uploader/src/applet.rs` for the uploader's side and the description of
+// the commands.
+//
+// The applet runs on the monitor's stack, and nothing but its code segment is
+// loaded, so it can't use any static variables.
+
+
+#![feature(lang_items)]
+
+#![no_main]
+#![no_std]
+
+
+use core::fmt;
+use core::ptr;
+
+use commands::Memory;
+
+
+mod commands;
+
+
+/// Entry point. The linker script puts this at the start of the applet.
+#[link_section = ".entry"]
+#[no_mangle]
+pub extern fn applet_main() {
+    commands::execute(&mut Device);
+}
+
+
+/// The memory of the device the applet runs on.
+struct Device;
+
+impl Memory for Device {
+    fn read(&mut self, address: u32) -> u32 {
+        unsafe { ptr::read_volatile(address as *const u32) }
+    }
+
+    fn write(&mut self, address: u32, value: u32) {
+        unsafe { ptr::write_volatile(address as *mut u32, value) }
+    }
+
+    fn read_byte(&mut self, address: u32) -> u8 {
+        unsafe { ptr::read_volatile(address as *const u8) }
+    }
+}
+
+
+// Language items required by the core library. See `blink/src/rust_base.rs`.
+// There's no way to report a panic from here, but the uploader will notice
+// that the applet doesn't return.
+
+#[lang = "panic_fmt"]
+pub extern fn rust_begin_unwind(
+    _message: fmt::Arguments,
+    _file   : &'static str,
+    _line   : u32,
+) -> ! {
+    loop {}
+}
+
+#[no_mangle] pub extern fn __aeabi_unwind_cpp_pr0() { loop {} }
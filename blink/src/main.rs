@@ -41,6 +41,7 @@ pub mod hardware {
 }
 
 pub mod interrupts;
+pub mod metadata;
 pub mod rust_base;
 pub mod volatile;
 
@@ -0,0 +1,35 @@
+// Metadata about the build, so the uploader can tell which program is on a
+// board. The linker script places it right after the vector table. See
+// uploader/src/metadata.rs for the layout, which must match the struct below.
+//
+// Only the magic number is set here. The rest can only be known after linking
+// (the length and CRC32 of the image), or can't be put into a static without
+// help from outside the compiler (version, git hash, build time). `./compile`
+// fills it in, using the uploader's stamp command.
+
+
+#[repr(C)]
+pub struct Metadata {
+    pub magic       : [u8; 8],
+    pub version     : [u8; 24],
+    pub git_hash    : [u8; 40],
+    pub timestamp   : u32,
+    pub image_length: u32,
+    pub crc32       : u32,
+    pub _reserved   : [u32; 1],
+}
+
+
+// Like the vector table, this needs `#[no_mangle]`, or LLVM will remove it, as
+// nothing in the program refers to it.
+#[link_section=".metadata"]
+#[no_mangle]
+pub static METADATA: Metadata = Metadata {
+    magic       : *b"BLNKMETA",
+    version     : [0; 24],
+    git_hash    : [0; 40],
+    timestamp   : 0,
+    image_length: 0,
+    crc32       : 0,
+    _reserved   : [0; 1],
+};
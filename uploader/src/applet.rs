@@ -0,0 +1,314 @@
+// Host side of the flashing applet in `applet/`. The applet is loaded into
+// SRAM and does the work that would otherwise take many round trips through
+// the SAM-BA monitor: Programming pages, erasing banks and computing CRC32
+// checksums over flash memory.
+//
+// The uploader and the applet communicate through a mailbox at a fixed
+// address in SRAM. The uploader writes a command and its arguments into the
+// mailbox, then starts the applet with the go command. The applet executes the
+// command, writes the status and result back into the mailbox, and returns to
+// the monitor.
+//
+// The applet's commands and the layout of the mailbox are in
+// `applet/src/commands.rs`, which is compiled into this module, too. That way,
+// the constants can't get out of sync, and the simulator can run the commands
+// (see `simulator` module).
+
+
+use std::fmt;
+use std::fs::File;
+use std::io::prelude::*;
+use std::path::Path;
+use std::time::Duration;
+
+use eefc;
+use eefc::Eefc;
+use elf;
+use result;
+use result::Result;
+use sam_ba::{
+    SamBa,
+    Transport,
+};
+
+
+#[path = "../../applet/src/commands.rs"]
+pub mod commands;
+
+// Offsets of the mailbox words, the commands (see `Applet` for their
+// arguments) and the values of the status word, besides the flash
+// controller's error flags.
+pub use self::commands::{
+    ARGS,
+    COMMAND,
+    CRC32,
+    CRC32_PAGES,
+    ERASE_ALL,
+    MAILBOX_ADDR,
+    RESULT,
+    STATUS,
+    STATUS_OK,
+    STATUS_UNKNOWN,
+    WRITE_PAGES,
+};
+
+
+// Layout of the rest of the SRAM used by the applet. The first 4 KiB of SRAM
+// belong to the SAM-BA monitor, the mailbox comes right after.
+//
+// Programs linked for SRAM (see `blink/linker-script-ram.ld`) start at the
+// same address as the mailbox. That's fine, as the applet is only used to
+// write the flash memory, which replaces whatever program was running anyway.
+pub const CODE_ADDR  : u32 = 0x20071040;
+pub const BUFFER_ADDR: u32 = 0x20072000;
+pub const BUFFER_SIZE: u32 = 0x2000;
+
+const CODE_SIZE: u32 = BUFFER_ADDR - CODE_ADDR;
+
+// The uploader sets the status to pending, before starting the applet.
+pub const STATUS_PENDING: u32 = 0xfffffffe;
+
+// How long to wait for a command to finish. Erasing a whole bank takes the
+// longest, and the flash controller's timeout covers that.
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+
+/// The applet, loaded into SRAM.
+pub struct Applet {
+    // Prevents construction without loading.
+    _private: (),
+}
+
+impl Applet {
+    /// Loads the applet from its ELF file, as built by the `compile` script.
+    /// Where that is, is up to the caller.
+    ///
+    /// The applet isn't position-independent, so this makes sure it was
+    /// linked for `CODE_ADDR`. See `applet/linker-script.ld` for why.
+    pub fn load_file<T, P>(sam_ba: &mut SamBa<T>, path: P) -> Result<Applet>
+        where
+            T: Transport,
+            P: AsRef<Path>,
+    {
+        let mut data = Vec::new();
+        let mut file = try!(File::open(path));
+        try!(file.read_to_end(&mut data));
+
+        let image    = try!(elf::parse(&data));
+        let segments = image.segments();
+
+        if segments.len() != 1 {
+            return Err(Error::Segments(segments.len()).into());
+        }
+        if segments[0].address != CODE_ADDR {
+            return Err(Error::Address(segments[0].address).into());
+        }
+
+        Applet::load(sam_ba, &segments[0].data)
+    }
+
+    /// Loads the applet's machine code into SRAM. The code must have been
+    /// linked for `CODE_ADDR`.
+    pub fn load<T: Transport>(sam_ba: &mut SamBa<T>, code: &[u8])
+        -> Result<Applet>
+    {
+        if code.len() as u32 > CODE_SIZE {
+            return Err(Error::TooLarge(code.len() as u32).into());
+        }
+
+        try!(sam_ba.send_file(CODE_ADDR, code));
+
+        Ok(Applet {
+            _private: (),
+        })
+    }
+
+    /// Writes consecutive pages of one bank. The data is sent to the buffer
+    /// first, so it can't be larger than `BUFFER_SIZE`. `page_number` is the
+    /// number of the first page, relative to the start of the bank.
+    pub fn write_pages<T>(&self,
+        sam_ba     : &mut SamBa<T>,
+        eefc       : &Eefc,
+        address    : u32,
+        page_number: u32,
+        page_size  : u32,
+        data       : &[u8],
+    )
+        -> Result<()>
+        where T: Transport
+    {
+        let number_of_pages = data.len() as u32 / page_size;
+
+        try!(sam_ba.send_file(BUFFER_ADDR, data));
+
+        let result = self.execute(sam_ba, WRITE_PAGES, &[
+            eefc.base(),
+            address,
+            page_number,
+            number_of_pages,
+            BUFFER_ADDR,
+            page_size,
+        ]);
+
+        // If the applet reports that writing a page failed, the result
+        // contains the page's index. After any other error, like a timeout,
+        // the result is meaningless.
+        match result {
+            Ok(_) =>
+                Ok(()),
+            Err(error @ result::Error::Eefc(_)) => {
+                let index   = try!(sam_ba.read_word(MAILBOX_ADDR + RESULT));
+                let address = address + index * page_size;
+                Err(result::Error::Page(address, Box::new(error)))
+            },
+            Err(error) =>
+                Err(error),
+        }
+    }
+
+    /// Erases the bank the flash controller is responsible for.
+    pub fn erase_all<T>(&self, sam_ba: &mut SamBa<T>, eefc: &Eefc)
+        -> Result<()>
+        where T: Transport
+    {
+        self.execute(sam_ba, ERASE_ALL, &[eefc.base()]).map(|_| ())
+    }
+
+    /// Computes the CRC32 of a range of memory on the device. See `crc32`.
+    pub fn crc32<T>(&self, sam_ba: &mut SamBa<T>, address: u32, length: u32)
+        -> Result<u32>
+        where T: Transport
+    {
+        self.execute(sam_ba, CRC32, &[address, length])
+    }
+
+    /// Computes the CRC32 of each of a number of consecutive pages. This
+    /// takes far fewer round trips than reading back the pages.
+    pub fn crc32_pages<T>(&self,
+        sam_ba         : &mut SamBa<T>,
+        address        : u32,
+        number_of_pages: u32,
+        page_size      : u32,
+    )
+        -> Result<Vec<u32>>
+        where T: Transport
+    {
+        try!(self.execute(sam_ba, CRC32_PAGES, &[
+            address,
+            number_of_pages,
+            page_size,
+            BUFFER_ADDR,
+        ]));
+
+        let mut crcs = vec![0; number_of_pages as usize * 4];
+        try!(sam_ba.receive_file(BUFFER_ADDR, &mut crcs));
+
+        Ok(
+            crcs
+                .chunks(4)
+                .map(|crc|
+                    crc.iter()
+                        .rev()
+                        .fold(0, |word, &byte| word << 8 | byte as u32)
+                )
+                .collect()
+        )
+    }
+
+    fn execute<T>(&self, sam_ba: &mut SamBa<T>, command: u32, args: &[u32])
+        -> Result<u32>
+        where T: Transport
+    {
+        for (i, &arg) in args.iter().enumerate() {
+            try!(sam_ba.write_word(MAILBOX_ADDR + ARGS + i as u32 * 4, arg));
+        }
+        try!(sam_ba.write_word(MAILBOX_ADDR + COMMAND, command));
+        try!(sam_ba.write_word(MAILBOX_ADDR + STATUS, STATUS_PENDING));
+
+        try!(sam_ba.go(CODE_ADDR | 0x1));
+
+        // The monitor only replies once the applet has returned. If part of
+        // the reply got lost, the applet has returned all the same, so the
+        // status can be read again once the connection is back in sync,
+        // without running the command again.
+        let address = MAILBOX_ADDR + STATUS;
+        let status  = match sam_ba.wait_for_word(address, TIMEOUT) {
+            Ok(status) =>
+                status,
+            Err(ref error) if error.is_communication() => {
+                try!(sam_ba.synchronize());
+                try!(sam_ba.read_word(address))
+            },
+            Err(error) =>
+                return Err(error),
+        };
+
+        match status {
+            STATUS_OK      => sam_ba.read_word(MAILBOX_ADDR + RESULT),
+            STATUS_PENDING => Err(Error::NotRunning.into()),
+            STATUS_UNKNOWN => Err(Error::UnknownCommand(command).into()),
+
+            // Anything else is the flash controller's status register.
+            status if status & 0x2 != 0 => Err(eefc::Error::Command.into()),
+            status if status & 0x4 != 0 => Err(eefc::Error::Lock.into()),
+            status => Err(eefc::Error::UnexpectedStatus(status).into()),
+        }
+    }
+}
+
+
+#[derive(Debug)]
+pub enum Error {
+    /// The applet doesn't fit into its space in SRAM. Contains its size.
+    TooLarge(u32),
+
+    /// The applet didn't update the status, so it probably didn't run.
+    NotRunning,
+
+    /// The applet doesn't know the command. Is it out of date?
+    UnknownCommand(u32),
+
+    /// The applet's ELF file has the given number of loadable segments,
+    /// instead of just one.
+    Segments(usize),
+
+    /// The applet is linked for the given address, instead of `CODE_ADDR`.
+    Address(u32),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::TooLarge(size) =>
+                write!(f, "Applet is too large ({} bytes)", size),
+            Error::NotRunning =>
+                write!(f, "Applet didn't run"),
+            Error::UnknownCommand(command) =>
+                write!(f, "Applet doesn't know command {}", command),
+            Error::Segments(number) =>
+                write!(f, "Applet has {} segments, expected 1", number),
+            Error::Address(address) =>
+                write!(f,
+                    "Applet is linked for 0x{:0>8X}, not 0x{:0>8X}",
+                    address, CODE_ADDR,
+                ),
+        }
+    }
+}
+
+
+/// Computes the CRC32 (as used by zlib, Ethernet, etc.) of the data. This is
+/// the same algorithm the applet uses.
+pub fn crc32(data: &[u8]) -> u32 {
+    let mut crc = 0xffffffff;
+
+    for &byte in data {
+        crc ^= byte as u32;
+
+        for _ in 0 .. 8 {
+            crc = if crc & 1 != 0 { crc >> 1 ^ 0xedb88320 } else { crc >> 1 };
+        }
+    }
+
+    !crc
+}
@@ -0,0 +1,55 @@
+//! Runs a simulated SAM-BA monitor on a pseudo-terminal. The path of the
+//! pseudo-terminal is printed on startup, and can be passed to the uploader
+//! in place of the real device.
+//!
+//! Please note that resetting the device through the 1200 baud touch isn't
+//! supported by pseudo-terminals, so pass `--no-erase` to `upload-file`.
+//!
+//! To test how the uploader deals with a bad connection, pass `--stray <text>`
+//! to have the simulator send some text before anything else, or
+//! `--lose-reply <n>` to make it lose a byte of the `n`th reply.
+//!
+//! Pass `--usb` to have the simulator transfer files without XMODEM, like the
+//! monitor does on the native USB port. The uploader can't tell that from the
+//! pseudo-terminal, so pass `--raw-transfers` to it as well.
+
+
+extern crate upload;
+
+
+use std::env;
+
+use upload::simulator::{
+    Pty,
+    Simulator,
+};
+
+
+fn main() {
+    let mut pty = Pty::open().expect("Failed to open pseudo-terminal");
+    let mut simulator = Simulator::new();
+
+    let mut args = env::args().skip(1);
+    while let Some(option) = args.next() {
+        match option.as_ref() {
+            "--stray" => {
+                let text = args.next().expect("Expected text argument");
+                simulator.inject(text.as_bytes());
+            },
+            "--usb" => {
+                simulator.set_raw_transfers(true);
+            },
+            "--lose-reply" => {
+                let n = args.next()
+                    .and_then(|n| n.parse().ok())
+                    .expect("Expected number argument");
+                simulator.lose_reply(n);
+            },
+            _ => panic!("Unknown option: {}", option),
+        }
+    }
+
+    print!("{}\n", pty.slave_path());
+
+    pty.serve(&mut simulator).expect("Failed to serve pseudo-terminal");
+}
@@ -0,0 +1,127 @@
+use result::Result;
+use sam_ba::{
+    SamBa,
+    Transport,
+};
+
+
+// Addresses of the Chip Identifier registers. See data sheet, section 28.3.
+pub const CIDR: u32 = 0x400e0940;
+const EXID: u32 = 0x400e0944;
+
+
+/// The contents of the Chip Identifier registers. See data sheet, chapter 28.
+pub struct ChipId {
+    pub cidr: u32,
+    pub exid: u32,
+}
+
+impl ChipId {
+    pub fn read<T: Transport>(sam_ba: &mut SamBa<T>) -> Result<ChipId> {
+        Ok(ChipId {
+            cidr: try!(sam_ba.read_word(CIDR)),
+            exid: try!(sam_ba.read_word(EXID)),
+        })
+    }
+
+    pub fn version(&self) -> u32 {
+        self.cidr & 0x1f
+    }
+
+    pub fn processor(&self) -> &'static str {
+        match self.cidr >> 5 & 0x7 {
+            1 => "ARM946ES",
+            2 => "ARM7TDMI",
+            3 => "Cortex-M3",
+            4 => "ARM920T",
+            5 => "ARM926EJS",
+            6 => "Cortex-A5",
+            7 => "Cortex-M4",
+            _ => "unknown",
+        }
+    }
+
+    /// Returns the size of the first non-volatile program memory in bytes.
+    pub fn flash_size(&self) -> Option<u32> {
+        nvp_size(self.cidr >> 8 & 0xf)
+    }
+
+    /// Returns the size of the second non-volatile program memory in bytes.
+    pub fn flash_size_2(&self) -> Option<u32> {
+        nvp_size(self.cidr >> 12 & 0xf)
+    }
+
+    /// Returns the size of the internal SRAM in bytes.
+    pub fn sram_size(&self) -> u32 {
+        let kib = match self.cidr >> 16 & 0xf {
+            0  => 48,
+            1  => 1,
+            2  => 2,
+            3  => 6,
+            4  => 24,
+            5  => 4,
+            6  => 80,
+            7  => 160,
+            8  => 8,
+            9  => 16,
+            10 => 32,
+            11 => 64,
+            12 => 128,
+            13 => 256,
+            14 => 96,
+            _  => 512,
+        };
+
+        kib * 1024
+    }
+
+    pub fn architecture(&self) -> u32 {
+        self.cidr >> 20 & 0xff
+    }
+
+    /// Returns the name of the architecture. Only the SAM3X/SAM3A families
+    /// are covered by the data sheet this is based on.
+    pub fn architecture_name(&self) -> &'static str {
+        match self.architecture() {
+            0x83 => "ATSAM3AxC",
+            0x84 => "ATSAM3XxC",
+            0x85 => "ATSAM3XxE",
+            0x86 => "ATSAM3XxG",
+            _    => "unknown",
+        }
+    }
+
+    pub fn memory_type(&self) -> &'static str {
+        match self.cidr >> 28 & 0x7 {
+            0 => "ROM",
+            1 => "ROMless or on-chip flash",
+            2 => "embedded flash",
+            3 => "ROM and embedded flash",
+            4 => "SRAM emulating ROM",
+            _ => "unknown",
+        }
+    }
+
+    /// Returns whether the extended chip ID register (EXID) exists.
+    pub fn has_extension(&self) -> bool {
+        self.cidr & 0x80000000 != 0
+    }
+}
+
+
+fn nvp_size(value: u32) -> Option<u32> {
+    let kib = match value {
+        1  => 8,
+        2  => 16,
+        3  => 32,
+        5  => 64,
+        7  => 128,
+        9  => 256,
+        10 => 512,
+        12 => 1024,
+        14 => 2048,
+        _  => return None,
+    };
+
+    Some(kib * 1024)
+}
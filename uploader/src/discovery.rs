@@ -0,0 +1,142 @@
+// Finds connected Arduino Dues by looking at the USB devices behind the
+// serial ports in sysfs. This only works on Linux.
+
+
+use std::fs;
+use std::io;
+use std::path::Path;
+
+
+// USB vendor and product IDs the uploader knows about.
+const ARDUINO          : u16 = 0x2341;
+const ATMEL            : u16 = 0x03eb;
+const DUE_PROGRAMMING  : u16 = 0x003d;
+const DUE_NATIVE       : u16 = 0x003e;
+const SAM_BA_BOOTLOADER: u16 = 0x6124;
+
+
+/// A serial port that belongs to a supported device.
+pub struct Device {
+    /// The path of the device file, e.g. `/dev/ttyACM0`.
+    pub path  : String,
+    pub kind  : Kind,
+    pub serial: Option<String>,
+}
+
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Kind {
+    /// The Due's programming port, which is connected to the ATmega16U2.
+    ProgrammingPort,
+
+    /// The Due's native USB port, which is connected to the SAM3X8E directly.
+    NativePort,
+
+    /// The SAM-BA bootloader itself, as seen on the native port after the
+    /// microcontroller has been reset into it.
+    Bootloader,
+}
+
+impl Kind {
+    fn from_ids(vendor_id: u16, product_id: u16) -> Option<Kind> {
+        match (vendor_id, product_id) {
+            (ARDUINO, DUE_PROGRAMMING)  => Some(Kind::ProgrammingPort),
+            (ARDUINO, DUE_NATIVE)       => Some(Kind::NativePort),
+            (ATMEL, SAM_BA_BOOTLOADER)  => Some(Kind::Bootloader),
+            _                           => None,
+        }
+    }
+
+    /// Returns whether the SAM-BA monitor behind the port transfers files
+    /// without XMODEM. It does so on the native USB port, where USB takes
+    /// care of detecting errors. See `SamBa::set_raw_transfers`.
+    pub fn raw_transfers(&self) -> bool {
+        match *self {
+            Kind::ProgrammingPort               => false,
+            Kind::NativePort | Kind::Bootloader => true,
+        }
+    }
+
+    pub fn description(&self) -> &'static str {
+        match *self {
+            Kind::ProgrammingPort => "Arduino Due (programming port)",
+            Kind::NativePort      => "Arduino Due (native port)",
+            Kind::Bootloader      => "SAM-BA bootloader",
+        }
+    }
+}
+
+
+/// Returns all serial ports that belong to a supported device, sorted by path.
+pub fn find() -> io::Result<Vec<Device>> {
+    let mut devices = Vec::new();
+
+    for entry in try!(fs::read_dir("/sys/class/tty")) {
+        let entry = try!(entry);
+
+        // Only ports that are backed by actual hardware have a device link.
+        // For USB serial ports, it points to the USB interface.
+        let interface = match fs::canonicalize(entry.path().join("device")) {
+            Ok(interface) => interface,
+            Err(_)        => continue,
+        };
+
+        // The vendor and product IDs are attributes of the USB device, which
+        // is one of the interface's ancestors.
+        let usb_device = interface
+            .ancestors()
+            .find(|path| path.join("idVendor").exists());
+
+        let usb_device = match usb_device {
+            Some(usb_device) => usb_device,
+            None             => continue,
+        };
+
+        let kind = read_id(usb_device, "idVendor")
+            .and_then(|vendor_id|
+                read_id(usb_device, "idProduct")
+                    .and_then(|product_id|
+                        Kind::from_ids(vendor_id, product_id)
+                    )
+            );
+
+        if let Some(kind) = kind {
+            devices.push(Device {
+                path  : format!("/dev/{}", entry.file_name().to_string_lossy()),
+                kind  : kind,
+                serial: read_attribute(usb_device, "serial"),
+            });
+        }
+    }
+
+    devices.sort_by(|a, b| a.path.cmp(&b.path));
+
+    Ok(devices)
+}
+
+
+/// Returns the kind of the device behind the given device file, if it belongs
+/// to a supported device.
+pub fn kind_of<P: AsRef<Path>>(path: P) -> io::Result<Option<Kind>> {
+    let path = try!(fs::canonicalize(path));
+
+    for device in try!(find()) {
+        if fs::canonicalize(&device.path).ok().as_ref() == Some(&path) {
+            return Ok(Some(device.kind));
+        }
+    }
+
+    Ok(None)
+}
+
+
+fn read_id(usb_device: &Path, name: &str) -> Option<u16> {
+    read_attribute(usb_device, name)
+        .and_then(|id| u16::from_str_radix(&id, 16).ok())
+}
+
+fn read_attribute(usb_device: &Path, name: &str) -> Option<String> {
+    fs::read_to_string(usb_device.join(name))
+        .ok()
+        .map(|value| value.trim().to_string())
+}
@@ -1,10 +1,31 @@
+use std::fmt;
+use std::time::{
+    Duration,
+    Instant,
+};
+
 use result::Result;
-use sam_ba::SamBa;
+use sam_ba::{
+    SamBa,
+    Transport,
+};
+
+
+// Flags in the status register. See data sheet, section 18.5.4.
+const FRDY  : u32 = 0x1;
+const FCMDE : u32 = 0x2;
+const FLOCKE: u32 = 0x4;
+
+// How long to wait for a command to finish. Erasing a whole bank is the
+// slowest command by far, and even that should take much less than this.
+// See data sheet, section 46.12.
+const TIMEOUT: Duration = Duration::from_secs(5);
 
 
 /// Provides an interface to the Enhanced Embedded Flash Controller (EEFC).
 /// See data sheet, chapter 18.
 pub struct Eefc {
+    mode_register   : u32,
     command_register: u32,
     status_register : u32,
     result_register : u32,
@@ -18,35 +39,266 @@ impl Eefc {
         let base = 0x400e0a00;
 
         Eefc {
+            mode_register   : base,
+            command_register: base + 0x04,
+            status_register : base + 0x08,
+            result_register : base + 0x0c,
+        }
+    }
+
+    /// Returns an instance that interfaces with the controller for the second
+    /// flash memory bank.
+    /// See data sheet, chapter 18.5.
+    pub fn eefc_1() -> Eefc {
+        let base = 0x400e0c00;
+
+        Eefc {
+            mode_register   : base,
             command_register: base + 0x04,
             status_register : base + 0x08,
             result_register : base + 0x0c,
         }
     }
 
+    /// Returns the base address of the controller's registers.
+    pub fn base(&self) -> u32 {
+        self.mode_register
+    }
+
+    /// Sets the number of wait states for flash read/write operations. See
+    /// data sheet, section 18.5.1.
+    pub fn set_wait_states<T>(&self, sam_ba: &mut SamBa<T>, wait_states: u8)
+        -> Result<()>
+        where T: Transport
+    {
+        sam_ba.write_word(self.mode_register, (wait_states as u32) << 8)
+    }
+
     /// Executes a flash command and returns its result. The commands and
     /// arguments that can be passed to this method are defined below.
     ///
     /// See data sheet chapter 18.4.3 for the list of commands, and chapter
     /// 18.5 (especially 18.5.2) for information about what's going on in this
     /// method.
-    pub fn execute_command<C, A>(&self, sam_ba: &mut SamBa, argument: A)
+    pub fn execute_command<C, A, T>(&self, sam_ba: &mut SamBa<T>, argument: A)
         -> Result<u32>
         where
             C: Command<Argument=A>,
             A: Argument,
+            T: Transport,
+    {
+        try!(self.write_command::<C, _, _>(sam_ba, argument));
+
+        // The error flags are cleared when the status register is read, so we
+        // need to check the value that has the ready flag set, instead of
+        // reading it again.
+        let start = Instant::now();
+        let status = loop {
+            let status = try!(sam_ba.read_register(self.status_register));
+
+            if status & FRDY != 0 {
+                break status;
+            }
+            if start.elapsed() > TIMEOUT {
+                return Err(Error::Timeout.into());
+            }
+        };
+
+        if status & FCMDE != 0 {
+            return Err(Error::Command.into());
+        }
+        if status & FLOCKE != 0 {
+            return Err(Error::Lock.into());
+        }
+        if status != FRDY {
+            return Err(Error::UnexpectedStatus(status).into());
+        }
+
+        sam_ba.read_register(self.result_register)
+    }
+
+    /// Reads the 128-bit unique identifier of the flash memory, which starts at
+    /// the given address. See data sheet, section 18.4.3.8.
+    ///
+    /// While the identifier is being read, reads from the flash memory return
+    /// the identifier instead of the flash contents. This is no problem for
+    /// the SAM-BA monitor, as it runs from ROM.
+    pub fn read_unique_id<T>(&self, sam_ba: &mut SamBa<T>, address: u32)
+        -> Result<[u8; 16]>
+        where T: Transport
+    {
+        sam_ba.retry(|sam_ba| self.read_unique_id_once(sam_ba, address))
+    }
+
+    fn read_unique_id_once<T>(&self, sam_ba: &mut SamBa<T>, address: u32)
+        -> Result<[u8; 16]>
+        where T: Transport
+    {
+        // The ready flag stays cleared until the identifier has been read, so
+        // we can't wait for it here.
+        try!(self.write_command::<StartReadUniqueIdentifier, _, _>(
+            sam_ba, (),
+        ));
+
+        let mut unique_id = [0; 16];
+        let result = sam_ba.read_memory(address, &mut unique_id);
+
+        // Make sure the flash memory is readable again, even if reading the
+        // identifier failed.
+        let stop = self.execute_command::<StopReadUniqueIdentifier, _, _>(
+            sam_ba, (),
+        );
+
+        try!(result);
+        try!(stop);
+
+        Ok(unique_id)
+    }
+
+    /// Reads the next word from the result register. Some commands return
+    /// more than one word, and this method can be used to read the words
+    /// following the one returned by `execute_command`.
+    pub fn read_result<T>(&self, sam_ba: &mut SamBa<T>) -> Result<u32>
+        where T: Transport
+    {
+        sam_ba.read_register(self.result_register)
+    }
+
+    /// Returns the descriptor of the flash memory this controller is
+    /// responsible for. See data sheet, section 18.4.3.1.
+    pub fn get_descriptor<T>(&self, sam_ba: &mut SamBa<T>)
+        -> Result<Descriptor>
+        where T: Transport
+    {
+        // The descriptor is read word by word from the result register, so if
+        // a read fails, the command needs to be repeated.
+        sam_ba.retry(|sam_ba| self.read_descriptor(sam_ba))
+    }
+
+    fn read_descriptor<T>(&self, sam_ba: &mut SamBa<T>)
+        -> Result<Descriptor>
+        where T: Transport
+    {
+        let id = try!(
+            self.execute_command::<GetFlashDescriptor, _, _>(sam_ba, ())
+        );
+
+        let size      = try!(self.read_result(sam_ba));
+        let page_size = try!(self.read_result(sam_ba));
+
+        let number_of_planes = try!(self.read_result(sam_ba));
+        let mut planes = Vec::new();
+        for _ in 0 .. number_of_planes {
+            planes.push(try!(self.read_result(sam_ba)));
+        }
+
+        let number_of_lock_regions = try!(self.read_result(sam_ba));
+        let mut lock_regions = Vec::new();
+        for _ in 0 .. number_of_lock_regions {
+            lock_regions.push(try!(self.read_result(sam_ba)));
+        }
+
+        Ok(Descriptor {
+            id          : id,
+            size        : size,
+            page_size   : page_size,
+            planes      : planes,
+            lock_regions: lock_regions,
+        })
+    }
+
+    /// Writes a command to the command register, without waiting for it to
+    /// finish.
+    fn write_command<C, A, T>(&self, sam_ba: &mut SamBa<T>, argument: A)
+        -> Result<()>
+        where
+            C: Command<Argument=A>,
+            A: Argument,
+            T: Transport,
     {
         let command =
             0x5a << 24
             | (argument.value() as u32) << 8
             | C::value() as u32;
 
-        try!(sam_ba.write_word(self.command_register, command));
+        sam_ba.write_word(self.command_register, command)
+    }
+
+    /// Returns the lock bits of all lock regions. Bit `n` of word `n / 32` in
+    /// the result is the lock bit of region `n`. See data sheet, section
+    /// 18.4.3.5.
+    pub fn get_lock_bits<T>(&self,
+        sam_ba           : &mut SamBa<T>,
+        number_of_regions: u32,
+    )
+        -> Result<Vec<u32>>
+        where T: Transport
+    {
+        sam_ba.retry(|sam_ba| self.read_lock_bits(sam_ba, number_of_regions))
+    }
 
-        while try!(sam_ba.read_word(self.status_register)) & 1 == 0 {}
-        assert_eq!(try!(sam_ba.read_word(self.status_register)), 1);
+    fn read_lock_bits<T>(&self,
+        sam_ba           : &mut SamBa<T>,
+        number_of_regions: u32,
+    )
+        -> Result<Vec<u32>>
+        where T: Transport
+    {
+        let mut lock_bits = vec![
+            try!(self.execute_command::<GetLockBit, _, _>(sam_ba, ()))
+        ];
 
-        sam_ba.read_word(self.result_register)
+        for _ in 1 .. number_of_regions.div_ceil(32) {
+            lock_bits.push(try!(self.read_result(sam_ba)));
+        }
+
+        Ok(lock_bits)
+    }
+}
+
+
+/// Describes the flash memory a controller is responsible for. All sizes are
+/// in bytes. See data sheet, section 18.4.3.1.
+pub struct Descriptor {
+    pub id       : u32,
+    pub size     : u32,
+    pub page_size: u32,
+
+    /// The size of each plane.
+    pub planes: Vec<u32>,
+
+    /// The size of each lock region.
+    pub lock_regions: Vec<u32>,
+}
+
+
+#[derive(Debug)]
+pub enum Error {
+    /// The controller didn't accept the command (FCMDE).
+    Command,
+
+    /// The command tried to modify a locked region (FLOCKE).
+    Lock,
+
+    /// The controller didn't finish executing the command in time.
+    Timeout,
+
+    /// The status register contains something other than what we expected.
+    UnexpectedStatus(u32),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::Command =>
+                write!(f, "Flash controller rejected the command"),
+            Error::Lock =>
+                write!(f, "Flash region is locked"),
+            Error::Timeout =>
+                write!(f, "Flash controller didn't respond in time"),
+            Error::UnexpectedStatus(status) =>
+                write!(f, "Unexpected flash status: 0x{:0>8X}", status),
+        }
     }
 }
 
@@ -62,6 +314,15 @@ pub trait Argument {
 }
 
 
+pub struct GetFlashDescriptor;
+
+impl Command for GetFlashDescriptor {
+    type Argument = ();
+
+    fn value() -> u8 { 0x00 }
+}
+
+
 pub struct ErasePageAndWritePage;
 
 impl Command for ErasePageAndWritePage {
@@ -80,6 +341,49 @@ impl Argument for Page {
 }
 
 
+pub struct EraseAll;
+
+impl Command for EraseAll {
+    type Argument = ();
+
+    fn value() -> u8 { 0x05 }
+}
+
+impl Argument for () {
+    fn value(self) -> u16 { 0 }
+}
+
+
+/// Locks the lock region that contains the given page. See data sheet,
+/// section 18.4.3.5.
+pub struct SetLockBit;
+
+impl Command for SetLockBit {
+    type Argument = Page;
+
+    fn value() -> u8 { 0x08 }
+}
+
+/// Unlocks the lock region that contains the given page.
+pub struct ClearLockBit;
+
+impl Command for ClearLockBit {
+    type Argument = Page;
+
+    fn value() -> u8 { 0x09 }
+}
+
+/// Returns the lock bits in the result register. Use `Eefc::get_lock_bits`,
+/// which takes care of reading all of them.
+pub struct GetLockBit;
+
+impl Command for GetLockBit {
+    type Argument = ();
+
+    fn value() -> u8 { 0x0a }
+}
+
+
 pub struct SetGpnvmBit;
 
 impl Command for SetGpnvmBit {
@@ -88,10 +392,53 @@ impl Command for SetGpnvmBit {
     fn value() -> u8 { 0x0b }
 }
 
+pub struct ClearGpnvmBit;
+
+impl Command for ClearGpnvmBit {
+    type Argument = GpnvmNumber;
+
+    fn value() -> u8 { 0x0c }
+}
+
+
+/// Returns the GPNVM bits in the result register. Bit 0 of the result is
+/// GPNVM bit 0, and so forth. See data sheet, section 18.4.3.6.
+pub struct GetGpnvmBit;
+
+impl Command for GetGpnvmBit {
+    type Argument = ();
+
+    fn value() -> u8 { 0x0d }
+}
+
+
+/// Use `Eefc::read_unique_id`, which takes care of the whole sequence.
+pub struct StartReadUniqueIdentifier;
+
+impl Command for StartReadUniqueIdentifier {
+    type Argument = ();
+
+    fn value() -> u8 { 0x0e }
+}
+
+pub struct StopReadUniqueIdentifier;
+
+impl Command for StopReadUniqueIdentifier {
+    type Argument = ();
+
+    fn value() -> u8 { 0x0f }
+}
+
+
+/// The GPNVM bits of the SAM3X8E. See data sheet, section 18.2.
+///
+/// Please note that setting the security bit can't be undone, except by
+/// erasing the whole chip through the ERASE pin.
+#[derive(Clone, Copy)]
 pub enum GpnvmNumber {
-    // Security          = 0,
+    Security          = 0,
     BootModeSelection = 1,
-    // FlashSelection    = 2,
+    FlashSelection    = 2,
 }
 
 impl Argument for GpnvmNumber {
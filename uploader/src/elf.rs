@@ -0,0 +1,309 @@
+// Loads images from ELF files, as produced by the linker. Only 32-bit
+// little-endian ARM executables are supported.
+//
+// The ELF format is described in the "Tool Interface Standard (TIS)
+// Executable and Linking Format (ELF) Specification", available at the
+// following URI:
+// http://refspecs.linuxbase.org/elf/elf.pdf
+
+
+use byteorder::{
+    ByteOrder,
+    LittleEndian,
+};
+
+use image::{
+    Image,
+    Segment,
+};
+
+
+const ELF_CLASS_32   : u8  = 1;
+const ELF_DATA_LSB   : u8  = 1;
+const ELF_TYPE_EXEC  : u16 = 2;
+const ELF_MACHINE_ARM: u16 = 40;
+
+const PT_LOAD: u32 = 1;
+
+
+/// Returns whether the data starts with the ELF magic number.
+pub fn is_elf(data: &[u8]) -> bool {
+    data.starts_with(b"\x7fELF")
+}
+
+/// Creates an image from the loadable segments of an ELF file.
+///
+/// Segments are placed at their physical address (LMA), not their virtual
+/// address (VMA). This matters for initialized data, which the linker script
+/// places in flash right after the code (`AT (_etext)`), while it's addressed
+/// in RAM, where the startup code copies it to.
+pub fn parse(data: &[u8]) -> Result<Image, Error> {
+    let mut segments = Vec::new();
+
+    for header in try!(program_headers(data)) {
+        // Segments that don't take up space in the file (like .bss) don't
+        // need to be written.
+        if header.segment_type != PT_LOAD || header.file_size == 0 {
+            continue;
+        }
+
+        segments.push(Segment {
+            address: header.physical_address,
+            data   : try!(
+                slice(data, header.file_offset, header.file_size)
+            ).to_vec(),
+        });
+    }
+
+    Image::new(segments).map_err(|_| Error::OutOfRange)
+}
+
+/// Returns where in the file the data at the given address is stored, if the
+/// file contains all of the `length` bytes at that address. Addresses are
+/// physical, like in `parse`.
+pub fn file_offset(data: &[u8], address: u32, length: u32)
+    -> Result<Option<usize>, Error>
+{
+    let end = try!(address.checked_add(length).ok_or(Error::OutOfRange));
+
+    for header in try!(program_headers(data)) {
+        let segment_start = header.physical_address;
+        let segment_end   = try!(
+            segment_start
+                .checked_add(header.file_size as u32)
+                .ok_or(Error::OutOfRange)
+        );
+
+        if header.segment_type == PT_LOAD
+            && address >= segment_start && end <= segment_end
+        {
+            return Ok(Some(
+                header.file_offset + (address - segment_start) as usize
+            ));
+        }
+    }
+
+    Ok(None)
+}
+
+
+#[derive(Debug)]
+pub enum Error {
+    NotElf,
+    Unsupported(&'static str),
+    Truncated,
+    OutOfRange,
+}
+
+
+fn slice(data: &[u8], offset: usize, length: usize) -> Result<&[u8], Error> {
+    match offset.checked_add(length) {
+        Some(end) if end <= data.len() => Ok(&data[offset .. end]),
+        _                              => Err(Error::Truncated),
+    }
+}
+
+
+struct ProgramHeader {
+    segment_type    : u32,
+    file_offset     : usize,
+    physical_address: u32,
+    file_size       : usize,
+}
+
+fn program_headers(data: &[u8]) -> Result<Vec<ProgramHeader>, Error> {
+    if !is_elf(data) || data.len() < 52 {
+        return Err(Error::NotElf);
+    }
+    if data[4] != ELF_CLASS_32 || data[5] != ELF_DATA_LSB {
+        return Err(Error::Unsupported("not a 32-bit little-endian file"));
+    }
+    if LittleEndian::read_u16(&data[16..]) != ELF_TYPE_EXEC {
+        return Err(Error::Unsupported("not an executable"));
+    }
+    if LittleEndian::read_u16(&data[18..]) != ELF_MACHINE_ARM {
+        return Err(Error::Unsupported("not an ARM file"));
+    }
+
+    let program_header_offset = LittleEndian::read_u32(&data[28..]) as usize;
+    let program_header_size   = LittleEndian::read_u16(&data[42..]) as usize;
+    let program_header_count  = LittleEndian::read_u16(&data[44..]) as usize;
+
+    let mut headers = Vec::new();
+
+    for i in 0 .. program_header_count {
+        let offset = try!(
+            (i * program_header_size)
+                .checked_add(program_header_offset)
+                .ok_or(Error::Truncated)
+        );
+        let header = try!(slice(data, offset, 32));
+
+        headers.push(ProgramHeader {
+            segment_type    : LittleEndian::read_u32(&header[0..]),
+            file_offset     : LittleEndian::read_u32(&header[4..]) as usize,
+            physical_address: LittleEndian::read_u32(&header[12..]),
+            file_size       : LittleEndian::read_u32(&header[16..]) as usize,
+        });
+    }
+
+    Ok(headers)
+}
+
+
+#[cfg(test)]
+pub mod tests {
+    use byteorder::{
+        ByteOrder,
+        LittleEndian,
+    };
+
+    use super::{
+        file_offset,
+        parse,
+        Error,
+        PT_LOAD,
+    };
+
+
+    /// A segment of an ELF file built by `build`.
+    pub struct Segment<'r> {
+        pub physical_address: u32,
+        pub virtual_address : u32,
+        pub data            : &'r [u8],
+        pub memory_size     : u32,
+    }
+
+    /// Builds an ARM executable with the given loadable segments, which are
+    /// stored after the program headers, in order.
+    pub fn build(segments: &[Segment]) -> Vec<u8> {
+        let mut data = vec![0; 52 + 32 * segments.len()];
+
+        data[.. 7].copy_from_slice(b"\x7fELF\x01\x01\x01");
+        LittleEndian::write_u16(&mut data[16..], 2);
+        LittleEndian::write_u16(&mut data[18..], 40);
+        LittleEndian::write_u32(&mut data[20..], 1);
+        LittleEndian::write_u32(&mut data[28..], 52);
+        LittleEndian::write_u16(&mut data[40..], 52);
+        LittleEndian::write_u16(&mut data[42..], 32);
+        LittleEndian::write_u16(&mut data[44..], segments.len() as u16);
+
+        for (i, segment) in segments.iter().enumerate() {
+            let offset = data.len() as u32;
+
+            let header = &mut data[52 + 32 * i ..];
+            LittleEndian::write_u32(&mut header[0..], PT_LOAD);
+            LittleEndian::write_u32(&mut header[4..], offset);
+            LittleEndian::write_u32(&mut header[8..], segment.virtual_address);
+            LittleEndian::write_u32(&mut header[12..],
+                segment.physical_address);
+            LittleEndian::write_u32(&mut header[16..],
+                segment.data.len() as u32);
+            LittleEndian::write_u32(&mut header[20..], segment.memory_size);
+
+            data.extend_from_slice(segment.data);
+        }
+
+        data
+    }
+
+
+    #[test]
+    fn places_segments_at_their_physical_address() {
+        let data = build(&[
+            Segment {
+                physical_address: 0x80000,
+                virtual_address : 0x80000,
+                data            : &[1, 2, 3, 4],
+                memory_size     : 4,
+            },
+            // Initialized data, which is copied from flash to RAM.
+            Segment {
+                physical_address: 0x80004,
+                virtual_address : 0x20070000,
+                data            : &[5, 6],
+                memory_size     : 2,
+            },
+            // Uninitialized data, which isn't part of the file.
+            Segment {
+                physical_address: 0x20070002,
+                virtual_address : 0x20070002,
+                data            : &[],
+                memory_size     : 0x100,
+            },
+        ]);
+
+        let image = parse(&data).unwrap();
+        let segments = image.segments();
+        assert_eq!(segments.len(), 2);
+        assert_eq!(segments[0].address, 0x80000);
+        assert_eq!(segments[0].data, [1, 2, 3, 4]);
+        assert_eq!(segments[1].address, 0x80004);
+        assert_eq!(segments[1].data, [5, 6]);
+
+        let headers_end = 52 + 3 * 32;
+        assert_eq!(file_offset(&data, 0x80002, 2).unwrap(),
+            Some(headers_end + 2));
+        assert_eq!(file_offset(&data, 0x80004, 2).unwrap(),
+            Some(headers_end + 4));
+        assert_eq!(file_offset(&data, 0x80003, 4).unwrap(), None);
+    }
+
+    #[test]
+    fn rejects_files_that_are_not_arm_executables() {
+        match parse(b"#!/bin/sh\n") {
+            Err(Error::NotElf) => (),
+            result => panic!("Unexpected result: {:?}", result.err()),
+        }
+
+        let mut data = build(&[]);
+        data[5] = 2;
+        match parse(&data) {
+            Err(Error::Unsupported(_)) => (),
+            result => panic!("Unexpected result: {:?}", result.err()),
+        }
+
+        let mut data = build(&[]);
+        data[18] = 62;
+        match parse(&data) {
+            Err(Error::Unsupported(_)) => (),
+            result => panic!("Unexpected result: {:?}", result.err()),
+        }
+    }
+
+    #[test]
+    fn rejects_truncated_files() {
+        let data = build(&[
+            Segment {
+                physical_address: 0x80000,
+                virtual_address : 0x80000,
+                data            : &[1, 2, 3, 4],
+                memory_size     : 4,
+            },
+        ]);
+
+        for &length in &[52 + 16, data.len() - 1] {
+            match parse(&data[.. length]) {
+                Err(Error::Truncated) => (),
+                result => panic!("Unexpected result: {:?}", result.err()),
+            }
+        }
+    }
+
+    #[test]
+    fn rejects_segments_beyond_the_end_of_the_address_space() {
+        let data = build(&[
+            Segment {
+                physical_address: 0xFFFFFFFE,
+                virtual_address : 0xFFFFFFFE,
+                data            : &[1, 2, 3, 4],
+                memory_size     : 4,
+            },
+        ]);
+
+        match parse(&data) {
+            Err(Error::OutOfRange) => (),
+            result => panic!("Unexpected result: {:?}", result.err()),
+        }
+    }
+}
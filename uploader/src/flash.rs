@@ -0,0 +1,819 @@
+use std::fmt;
+
+use applet;
+use applet::Applet;
+use eefc::{
+    ClearLockBit,
+    Descriptor,
+    Eefc,
+    EraseAll,
+    ErasePageAndWritePage,
+    Page,
+    SetLockBit,
+};
+use image;
+use image::Image;
+use ram;
+use result::{
+    Error,
+    Result,
+};
+use sam_ba::{
+    SamBa,
+    Transport,
+};
+
+
+// Base address of the internal flash memory. See data sheet, section 7.1.
+pub const BASE_ADDR: u32 = 0x00080000;
+
+// The size of the Cortex-M3's vector table, without any device-specific
+// interrupts. No image can be smaller than this and still be a working program.
+const MIN_IMAGE_SIZE: u32 = 16 * 4;
+
+
+/// The internal flash memory. The SAM3X8E's flash memory is divided into two
+/// banks, each of which has its own controller. The layout of the banks is
+/// queried from the controllers, instead of being hardcoded.
+/// See sections 7.2.3 and chapter 18 in the data sheet.
+pub struct Flash {
+    banks : Vec<Bank>,
+    applet: Option<Applet>,
+}
+
+impl Flash {
+    /// Queries the flash descriptors from both controllers. The banks are
+    /// located right after each other, starting at the base address.
+    pub fn read<T: Transport>(sam_ba: &mut SamBa<T>) -> Result<Flash> {
+        let mut banks   = Vec::new();
+        let mut address = BASE_ADDR;
+
+        for eefc in [Eefc::eefc_0(), Eefc::eefc_1()] {
+            let descriptor = try!(eefc.get_descriptor(sam_ba));
+            let size       = descriptor.size;
+
+            banks.push(Bank {
+                eefc      : eefc,
+                address   : address,
+                descriptor: descriptor,
+            });
+
+            address += size;
+        }
+
+        Ok(Flash {
+            banks : banks,
+            applet: None,
+        })
+    }
+
+    /// Makes all following operations use the applet, which is much faster.
+    /// See `applet` module.
+    pub fn use_applet(&mut self, applet: Applet) {
+        self.applet = Some(applet);
+    }
+
+    /// Returns the applet, if `use_applet` was called.
+    pub fn applet(&self) -> Option<&Applet> {
+        self.applet.as_ref()
+    }
+
+    pub fn banks(&self) -> &[Bank] {
+        &self.banks
+    }
+
+    /// Returns the size of the whole flash memory in bytes.
+    pub fn size(&self) -> u32 {
+        self.banks
+            .iter()
+            .fold(0, |size, bank| size + bank.descriptor.size)
+    }
+
+    /// Returns the page size in bytes. The page size is the same for all
+    /// banks.
+    pub fn page_size(&self) -> u32 {
+        self.banks[0].descriptor.page_size
+    }
+
+    /// Returns whether the given range of memory lies within the flash memory.
+    pub fn contains(&self, address: u32, length: u32) -> bool {
+        address >= BASE_ADDR
+            && length <= self.size()
+            && address - BASE_ADDR <= self.size() - length
+    }
+
+    /// Makes sure all segments of the image lie within the flash memory.
+    /// `write`, `write_changed` and `verify` do this before touching the
+    /// device.
+    pub fn check_bounds(&self, image: &Image) -> Result<()> {
+        for segment in image.segments() {
+            let length = segment.data.len() as u32;
+
+            if !self.contains(segment.address, length) {
+                return Err(
+                    CheckError::OutsideFlash(segment.address, length).into()
+                );
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Makes sure the image looks like a program that can start from the flash
+    /// memory. This catches empty or mislinked images, before they are
+    /// written. The image's vector table is expected at its lowest address.
+    pub fn check(&self, image: &Image) -> Result<()> {
+        try!(self.check_bounds(image));
+
+        if image.size() < MIN_IMAGE_SIZE {
+            return Err(CheckError::TooSmall(image.size()).into());
+        }
+
+        let vector_table = image.segments()
+            .iter()
+            .map(|segment| segment.address)
+            .min()
+            .unwrap_or(BASE_ADDR);
+
+        let (stack_pointer, reset_handler) = match (
+            image.read_word(vector_table),
+            image.read_word(vector_table + 4),
+        ) {
+            (Some(stack_pointer), Some(reset_handler)) =>
+                (stack_pointer, reset_handler),
+            _ =>
+                return Err(CheckError::NoVectorTable.into()),
+        };
+
+        if !ram::is_stack_pointer(stack_pointer) {
+            return Err(CheckError::StackPointer(stack_pointer).into());
+        }
+        if reset_handler & 0x1 == 0 || !self.contains(reset_handler & !0x1, 2) {
+            return Err(CheckError::ResetHandler(reset_handler).into());
+        }
+
+        Ok(())
+    }
+
+    /// Returns all lock regions of all banks, in order, along with whether
+    /// they are locked.
+    pub fn lock_regions<T>(&self, sam_ba: &mut SamBa<T>)
+        -> Result<Vec<LockRegion>>
+        where T: Transport
+    {
+        let mut lock_regions = Vec::new();
+
+        for (i, bank) in self.banks.iter().enumerate() {
+            let sizes     = &bank.descriptor.lock_regions;
+            let lock_bits = try!(
+                bank.eefc.get_lock_bits(sam_ba, sizes.len() as u32)
+            );
+
+            let mut address = bank.address;
+            for (region, &size) in sizes.iter().enumerate() {
+                lock_regions.push(LockRegion {
+                    bank   : i,
+                    address: address,
+                    size   : size,
+                    locked : lock_bits[region / 32] & 1 << (region % 32) != 0,
+                });
+
+                address += size;
+            }
+        }
+
+        Ok(lock_regions)
+    }
+
+    /// Locks all lock regions that overlap the given range of memory. Returns
+    /// the number of regions affected.
+    pub fn lock<T>(&self, sam_ba: &mut SamBa<T>, address: u32, length: u32)
+        -> Result<u32>
+        where T: Transport
+    {
+        self.set_locked(sam_ba, address, length, true)
+    }
+
+    /// Unlocks all lock regions that overlap the given range of memory.
+    /// Returns the number of regions affected.
+    pub fn unlock<T>(&self, sam_ba: &mut SamBa<T>, address: u32, length: u32)
+        -> Result<u32>
+        where T: Transport
+    {
+        self.set_locked(sam_ba, address, length, false)
+    }
+
+    /// Fails with `Error::Locked`, if any part of the image lies within a
+    /// locked region. Writing a page in a locked region would fail anyway,
+    /// but checking first makes sure we don't leave a partially written image
+    /// behind.
+    pub fn check_locks<T>(&self, sam_ba: &mut SamBa<T>, image: &Image)
+        -> Result<()>
+        where T: Transport
+    {
+        for region in try!(self.lock_regions(sam_ba)) {
+            let overlaps = image.segments()
+                .iter()
+                .any(|segment|
+                    region.overlaps(segment.address, segment.data.len() as u32)
+                );
+
+            if region.locked && overlaps {
+                return Err(Error::Locked(region.address));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Erases the flash memory, except for locked regions.
+    ///
+    /// Banks without locked regions are erased as a whole. The controller
+    /// refuses to do that, if any region of the bank is locked, so the other
+    /// regions of such a bank are erased by writing pages of 0xff instead.
+    pub fn erase<T: Transport>(&self, sam_ba: &mut SamBa<T>) -> Result<()> {
+        let lock_regions = try!(self.lock_regions(sam_ba));
+
+        for (i, bank) in self.banks.iter().enumerate() {
+            let regions = lock_regions
+                .iter()
+                .filter(|region| region.bank == i);
+
+            if regions.clone().any(|region| region.locked) {
+                let unlocked = regions
+                    .filter(|region| !region.locked)
+                    .map(|region| image::Segment {
+                        address: region.address,
+                        data   : vec![0xff; region.size as usize],
+                    })
+                    .collect();
+
+                try!(self.write(sam_ba, &try!(Image::new(unlocked)), |_| ()));
+                continue;
+            }
+
+            match self.applet {
+                Some(ref applet) => {
+                    try!(applet.erase_all(sam_ba, &bank.eefc));
+                },
+                None => {
+                    try!(bank.eefc.execute_command::<EraseAll, _, _>(
+                        sam_ba, (),
+                    ));
+                },
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Writes an image to the flash memory and returns the number of pages
+    /// written. Only pages that contain data from the image are written.
+    /// `progress` is called after each page.
+    pub fn write<T, F>(&self,
+        sam_ba      : &mut SamBa<T>,
+        image       : &Image,
+        mut progress: F,
+    )
+        -> Result<u32>
+        where
+            T: Transport,
+            F: FnMut(Progress),
+    {
+        try!(self.check_bounds(image));
+        try!(self.set_wait_states(sam_ba));
+
+        let pages = image.pages(self.page_size());
+
+        let mut done = 0;
+        for run in try!(self.runs(&pages)) {
+            try!(self.write_run(sam_ba, run));
+
+            for page in run {
+                progress(
+                    Progress::new(page, Action::Written, done, pages.len())
+                );
+                done += 1;
+            }
+        }
+
+        Ok(pages.len() as u32)
+    }
+
+    /// Writes an image to the flash memory, like `write`, but reads back each
+    /// page first and skips the pages that already contain the image's data.
+    /// This only makes sense, if the flash memory hasn't been erased before.
+    pub fn write_changed<T, F>(&self,
+        sam_ba      : &mut SamBa<T>,
+        image       : &Image,
+        mut progress: F,
+    )
+        -> Result<Written>
+        where
+            T: Transport,
+            F: FnMut(Progress),
+    {
+        try!(self.check_bounds(image));
+        try!(self.set_wait_states(sam_ba));
+
+        let mut written = Written {
+            pages_written: 0,
+            pages_skipped: 0,
+        };
+
+        let pages = image.pages(self.page_size());
+
+        let mut done = 0;
+        for run in try!(self.runs(&pages)) {
+            let mismatches = try!(self.compare_run(sam_ba, run));
+
+            // Write the changed pages in as few runs as possible.
+            let mut i = 0;
+            while i < run.len() {
+                let start = i;
+                while i < run.len() && mismatches[i].is_some() {
+                    i += 1;
+                }
+
+                if i > start {
+                    try!(self.write_run(sam_ba, &run[start .. i]));
+                }
+                else {
+                    i += 1;
+                }
+
+                for page in &run[start .. i] {
+                    let action = if mismatches[start].is_some() {
+                        written.pages_written += 1;
+                        Action::Written
+                    }
+                    else {
+                        written.pages_skipped += 1;
+                        Action::Skipped
+                    };
+
+                    progress(Progress::new(page, action, done, pages.len()));
+                    done += 1;
+                }
+            }
+        }
+
+        Ok(written)
+    }
+
+    /// Reads back the flash memory and compares it against the image.
+    /// `progress` is called after each page.
+    pub fn verify<T, F>(&self,
+        sam_ba      : &mut SamBa<T>,
+        image       : &Image,
+        mut progress: F,
+    )
+        -> Result<Verification>
+        where
+            T: Transport,
+            F: FnMut(Progress),
+    {
+        try!(self.check_bounds(image));
+
+        let mut verification = Verification {
+            first_mismatch  : None,
+            mismatched_pages: 0,
+        };
+
+        let pages = image.pages(self.page_size());
+
+        let mut done = 0;
+        for run in try!(self.runs(&pages)) {
+            let mismatches = try!(self.compare_run(sam_ba, run));
+
+            for (page, mismatch) in run.iter().zip(mismatches) {
+                if let Some(offset) = mismatch {
+                    verification.mismatched_pages += 1;
+
+                    if verification.first_mismatch.is_none() {
+                        verification.first_mismatch =
+                            Some(page.address + offset as u32);
+                    }
+                }
+
+                progress(
+                    Progress::new(page, Action::Verified, done, pages.len())
+                );
+                done += 1;
+            }
+        }
+
+        Ok(verification)
+    }
+
+    fn set_wait_states<T>(&self, sam_ba: &mut SamBa<T>) -> Result<()>
+        where T: Transport
+    {
+        // This sets the number of wait states for flash read/write operations
+        // to 6. See data sheet, section 18.5.1. According to the errata
+        // section, this is required. Otherwise data written can be corrupted.
+        // See section 49.1.1.1.
+        // Please note that I wasn't able to verify that this really is
+        // necessary. However, I was testing with a binary that wasn't
+        // optimized. It is probable that flipping some bits here or there
+        // wouldn't inhibit the functioning of that binary.
+        for bank in &self.banks {
+            try!(bank.eefc.set_wait_states(sam_ba, 6));
+        }
+
+        Ok(())
+    }
+
+    fn set_locked<T>(&self,
+        sam_ba : &mut SamBa<T>,
+        address: u32,
+        length : u32,
+        locked : bool,
+    )
+        -> Result<u32>
+        where T: Transport
+    {
+        let mut affected = 0;
+
+        for region in try!(self.lock_regions(sam_ba)) {
+            if !region.overlaps(address, length) {
+                continue;
+            }
+
+            // The controller identifies lock regions by any of their pages.
+            // Pages are numbered relative to the start of their bank.
+            let bank = &self.banks[region.bank];
+            let page = Page(
+                ((region.address - bank.address) / bank.descriptor.page_size)
+                    as u16
+            );
+
+            if locked {
+                try!(bank.eefc.execute_command::<SetLockBit, _, _>(
+                    sam_ba, page,
+                ));
+            }
+            else {
+                try!(bank.eefc.execute_command::<ClearLockBit, _, _>(
+                    sam_ba, page,
+                ));
+            }
+
+            affected += 1;
+        }
+
+        Ok(affected)
+    }
+
+    /// Splits the pages into runs that can be handled at once. Without the
+    /// applet, every page is a run of its own. With the applet, a run consists
+    /// of consecutive pages of the same bank that fit into its buffer.
+    fn runs<'a>(&self, pages: &'a [image::Page])
+        -> Result<Vec<&'a [image::Page]>>
+    {
+        let page_size = self.page_size();
+        let max_len   = match self.applet {
+            Some(_) => (applet::BUFFER_SIZE / page_size) as usize,
+            None    => 1,
+        };
+
+        let mut runs  = Vec::new();
+        let mut start = 0;
+        for i in 1 .. pages.len() + 1 {
+            let ends_here = i == pages.len()
+                || i - start == max_len
+                || pages[i].address != pages[i - 1].address + page_size
+                || try!(self.bank_for(pages[i].address)).address
+                    != try!(self.bank_for(pages[start].address)).address;
+
+            if ends_here {
+                runs.push(&pages[start .. i]);
+                start = i;
+            }
+        }
+
+        Ok(runs)
+    }
+
+    /// Writes a run of pages, as returned by `runs`. Bytes of the pages that
+    /// aren't part of the image keep their current contents.
+    fn write_run<T>(&self, sam_ba: &mut SamBa<T>, run: &[image::Page])
+        -> Result<()>
+        where T: Transport
+    {
+        let bank      = try!(self.bank_for(run[0].address));
+        let page_size = bank.descriptor.page_size;
+
+        // Pages are numbered relative to the start of their bank.
+        let page_number = (run[0].address - bank.address) / page_size;
+
+        match self.applet {
+            Some(ref applet) => {
+                let mut data = Vec::new();
+                for page in run {
+                    data.extend_from_slice(&try!(page_data(sam_ba, page)));
+                }
+
+                applet.write_pages(
+                    sam_ba,
+                    &bank.eefc,
+                    run[0].address,
+                    page_number,
+                    page_size,
+                    &data,
+                )
+            },
+
+            None => {
+                for (i, page) in run.iter().enumerate() {
+                    let page_number = page_number + i as u32;
+                    let data        = try!(page_data(sam_ba, page));
+
+                    // The page is sent again on retry, in case the latch
+                    // buffer doesn't contain it anymore.
+                    try!(
+                        sam_ba
+                            .retry(|sam_ba|
+                                write_page(
+                                    sam_ba, bank, page_number, page.address,
+                                    &data,
+                                )
+                            )
+                            .map_err(|error|
+                                Error::Page(page.address, Box::new(error))
+                            )
+                    );
+                }
+
+                Ok(())
+            },
+        }
+    }
+
+    /// Compares a run of pages, as returned by `runs`, against the flash
+    /// memory. Returns the offset of the first mismatching byte of each page,
+    /// if any.
+    ///
+    /// With the applet, only the checksums of the pages are transferred. Only
+    /// pages whose checksum doesn't match are read back, to find out whether
+    /// any bytes that are part of the image differ, and which.
+    fn compare_run<T>(&self, sam_ba: &mut SamBa<T>, run: &[image::Page])
+        -> Result<Vec<Option<usize>>>
+        where T: Transport
+    {
+        let crcs = match self.applet {
+            Some(ref applet) =>
+                Some(try!(applet.crc32_pages(
+                    sam_ba,
+                    run[0].address,
+                    run.len() as u32,
+                    self.page_size(),
+                ))),
+            None =>
+                None,
+        };
+
+        let mut mismatches = Vec::with_capacity(run.len());
+        for (i, page) in run.iter().enumerate() {
+            let unchanged = match crcs {
+                Some(ref crcs) => crcs[i] == applet::crc32(&page.data),
+                None           => false,
+            };
+
+            if unchanged {
+                mismatches.push(None);
+            }
+            else {
+                mismatches.push(try!(compare_page(sam_ba, page)));
+            }
+        }
+
+        Ok(mismatches)
+    }
+
+    /// Returns the bank that contains the given address.
+    fn bank_for(&self, address: u32) -> Result<&Bank> {
+        self.banks
+            .iter()
+            .find(|bank| {
+                address >= bank.address
+                    && address - bank.address < bank.descriptor.size
+            })
+            .ok_or(CheckError::OutsideFlash(address, 1).into())
+    }
+}
+
+
+/// A bank of flash memory, along with the controller that is responsible for
+/// it.
+pub struct Bank {
+    pub eefc      : Eefc,
+    pub address   : u32,
+    pub descriptor: Descriptor,
+}
+
+
+/// A region of flash memory that can be locked against writing and erasing.
+/// See data sheet, section 18.4.3.5.
+pub struct LockRegion {
+    /// The index of the bank the region belongs to.
+    pub bank   : usize,
+    pub address: u32,
+    pub size   : u32,
+    pub locked : bool,
+}
+
+impl LockRegion {
+    /// Returns whether the region overlaps the given range of memory.
+    pub fn overlaps(&self, address: u32, length: u32) -> bool {
+        length > 0
+            && self.address < address.saturating_add(length)
+            && address < self.address + self.size
+    }
+}
+
+
+/// The reason an image didn't pass `Flash::check` or `Flash::check_bounds`.
+#[derive(Debug)]
+pub enum CheckError {
+    /// A segment doesn't lie within the flash memory. Contains its address
+    /// and length.
+    OutsideFlash(u32, u32),
+
+    /// The image is smaller than a vector table. Contains the image size.
+    TooSmall(u32),
+
+    /// The image doesn't contain the first two entries of a vector table.
+    NoVectorTable,
+
+    /// The initial stack pointer doesn't point into SRAM.
+    StackPointer(u32),
+
+    /// The reset handler isn't a Thumb address within the flash memory.
+    ResetHandler(u32),
+}
+
+impl fmt::Display for CheckError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            CheckError::OutsideFlash(address, length) =>
+                write!(f,
+                    "Segment at 0x{:0>8X} ({} bytes) is outside of flash \
+                    memory",
+                    address, length,
+                ),
+            CheckError::TooSmall(size) =>
+                write!(f,
+                    "Image is only {} bytes, too small for a vector table. \
+                    Was the vector table optimized away?",
+                    size,
+                ),
+            CheckError::NoVectorTable =>
+                write!(f, "Image doesn't start with a vector table"),
+            CheckError::StackPointer(address) =>
+                write!(f,
+                    "Initial stack pointer 0x{:0>8X} doesn't point into SRAM",
+                    address,
+                ),
+            CheckError::ResetHandler(address) =>
+                write!(f,
+                    "Reset handler 0x{:0>8X} isn't a Thumb address in flash",
+                    address,
+                ),
+        }
+    }
+}
+
+
+/// Reported to the caller after each page that `Flash::write`,
+/// `Flash::write_changed` or `Flash::verify` processed.
+pub struct Progress {
+    pub address: u32,
+    pub action : Action,
+
+    /// The number of pages processed so far, including this one.
+    pub pages_done: u32,
+
+    /// The number of pages that will be processed in total.
+    pub pages_total: u32,
+
+    /// The number of bytes that were transferred for this page.
+    pub bytes: u32,
+}
+
+impl Progress {
+    fn new(page: &image::Page, action: Action, index: usize, total: usize)
+        -> Self
+    {
+        Progress {
+            address    : page.address,
+            action     : action,
+            pages_done : index as u32 + 1,
+            pages_total: total as u32,
+            bytes      : page.data.len() as u32,
+        }
+    }
+}
+
+/// What happened to a page.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Action {
+    Written,
+    Skipped,
+    Verified,
+}
+
+impl Action {
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Action::Written  => "written",
+            Action::Skipped  => "skipped",
+            Action::Verified => "verified",
+        }
+    }
+}
+
+
+/// The result of writing only the changed pages of an image.
+pub struct Written {
+    pub pages_written: u32,
+
+    /// The number of pages that weren't written, because the flash memory
+    /// already contained the image's data.
+    pub pages_skipped: u32,
+}
+
+
+/// The result of comparing the flash memory against an image.
+pub struct Verification {
+    /// The address of the first byte that doesn't match the image.
+    pub first_mismatch: Option<u32>,
+
+    /// The number of pages that contain at least one mismatching byte.
+    pub mismatched_pages: u32,
+}
+
+
+/// Returns the data to write into a page. The controller can only write whole
+/// pages, so bytes that aren't part of the image are read back from the flash
+/// memory, instead of overwriting them with 0xff.
+fn page_data<T>(sam_ba: &mut SamBa<T>, page: &image::Page)
+    -> Result<Vec<u8>>
+    where T: Transport
+{
+    let mut data = page.data.clone();
+
+    if page.used.iter().all(|&used| used) {
+        return Ok(data);
+    }
+
+    let mut current = vec![0; data.len()];
+    try!(sam_ba.receive_file(page.address, &mut current));
+
+    for (i, byte) in data.iter_mut().enumerate() {
+        if !page.used[i] {
+            *byte = current[i];
+        }
+    }
+
+    Ok(data)
+}
+
+fn write_page<T>(
+    sam_ba     : &mut SamBa<T>,
+    bank       : &Bank,
+    page_number: u32,
+    address    : u32,
+    data       : &[u8],
+)
+    -> Result<()>
+    where T: Transport
+{
+    // Writing to the flash memory fills the controller's latch buffer. The
+    // data is only programmed once we issue the command below.
+    try!(sam_ba.send_file(address, data));
+
+    try!(bank.eefc.execute_command::<ErasePageAndWritePage, _, _>(
+        sam_ba,
+        Page(page_number as u16),
+    ));
+
+    Ok(())
+}
+
+/// Reads back a page and returns the offset of the first byte that doesn't
+/// match the image, if any.
+fn compare_page<T>(sam_ba: &mut SamBa<T>, page: &image::Page)
+    -> Result<Option<usize>>
+    where T: Transport
+{
+    let mut data = vec![0; page.data.len()];
+    try!(sam_ba.receive_file(page.address, &mut data));
+
+    // Bytes that are not part of the image are not compared. The image makes
+    // no claim about them.
+    Ok(
+        (0 .. data.len())
+            .position(|i| page.used[i] && page.data[i] != data[i])
+    )
+}
@@ -0,0 +1,240 @@
+use std::io;
+use std::io::prelude::*;
+
+use image::Image;
+use utils::parse_hex_bytes;
+
+
+/// Creates an image from a file in the Intel HEX format. Regions that aren't
+/// covered by any data record are not part of the image.
+pub fn parse(text: &str) -> Result<Image, Error> {
+    let mut image = Image::empty();
+
+    // The upper address bits, as set by extended segment address (02) and
+    // extended linear address (04) records.
+    let mut base_address = 0;
+
+    for (i, line) in text.lines().enumerate() {
+        let line_number = i + 1;
+
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        if !line.starts_with(':') {
+            return Err(Error::InvalidRecord(line_number));
+        }
+
+        let bytes = try!(
+            parse_hex_bytes(&line[1..]).ok_or(Error::InvalidRecord(line_number))
+        );
+        if bytes.len() < 5 || bytes.len() != bytes[0] as usize + 5 {
+            return Err(Error::InvalidRecord(line_number));
+        }
+
+        // The checksum is chosen so the sum of all bytes of the record is 0.
+        let sum = bytes.iter().fold(0u8, |sum, &byte| sum.wrapping_add(byte));
+        if sum != 0 {
+            return Err(Error::Checksum(line_number));
+        }
+
+        let address     = (bytes[1] as u32) << 8 | bytes[2] as u32;
+        let record_type = bytes[3];
+        let data        = &bytes[4 .. bytes.len() - 1];
+
+        match record_type {
+            0x00 =>
+                try!(
+                    image.add(base_address + address, data)
+                        .map_err(|_| Error::OutOfRange(line_number))
+                ),
+            0x01 =>
+                return Ok(image),
+            0x02 if data.len() == 2 =>
+                base_address = ((data[0] as u32) << 8 | data[1] as u32) << 4,
+            0x04 if data.len() == 2 =>
+                base_address = ((data[0] as u32) << 8 | data[1] as u32) << 16,
+
+            // Start address records are only relevant for x86 and for
+            // programs that are loaded by an operating system. The
+            // microcontroller takes its start address from the vector table.
+            0x03 | 0x05 =>
+                (),
+
+            _ =>
+                return Err(Error::InvalidRecord(line_number)),
+        }
+    }
+
+    Err(Error::MissingEndOfFile)
+}
+
+
+#[derive(Debug)]
+pub enum Error {
+    /// The record on the given line is malformed or of an unknown type.
+    InvalidRecord(usize),
+
+    /// The checksum of the record on the given line doesn't match.
+    Checksum(usize),
+
+    /// The data of the record on the given line extends beyond the end of the
+    /// address space.
+    OutOfRange(usize),
+
+    /// The file ended without an end of file record.
+    MissingEndOfFile,
+}
+
+
+/// Writes data in the Intel HEX format.
+///
+/// Only the record types that are needed for 32-bit addresses are used: data
+/// records (00), the end of file record (01) and extended linear address
+/// records (04).
+pub struct Writer<W> {
+    output       : W,
+    upper_address: Option<u16>,
+}
+
+impl<W: Write> Writer<W> {
+    pub fn new(output: W) -> Self {
+        Writer {
+            output       : output,
+            upper_address: None,
+        }
+    }
+
+    /// Writes data that is located at the given address. Data records never
+    /// cross a 64 KiB boundary, so extended linear address records are emitted
+    /// as required.
+    pub fn write(&mut self, mut address: u32, mut data: &[u8])
+        -> io::Result<()>
+    {
+        while !data.is_empty() {
+            let upper = (address >> 16) as u16;
+            let lower = address as u16;
+
+            if self.upper_address != Some(upper) {
+                let upper_bytes = [(upper >> 8) as u8, upper as u8];
+                try!(self.write_record(0x04, 0, &upper_bytes));
+                self.upper_address = Some(upper);
+            }
+
+            let until_boundary = 0x10000 - lower as usize;
+            let length         = *[16, data.len(), until_boundary].iter()
+                .min()
+                .unwrap();
+
+            try!(self.write_record(0x00, lower, &data[.. length]));
+
+            address = address.wrapping_add(length as u32);
+            data    = &data[length ..];
+        }
+
+        Ok(())
+    }
+
+    /// Writes the end of file record. No data must be written after this.
+    pub fn finish(mut self) -> io::Result<W> {
+        try!(self.write_record(0x01, 0, &[]));
+        Ok(self.output)
+    }
+
+    fn write_record(&mut self, record_type: u8, address: u16, data: &[u8])
+        -> io::Result<()>
+    {
+        let mut checksum = data.len() as u8;
+        checksum = checksum.wrapping_add((address >> 8) as u8);
+        checksum = checksum.wrapping_add(address as u8);
+        checksum = checksum.wrapping_add(record_type);
+
+        try!(write!(
+            self.output,
+            ":{:0>2X}{:0>4X}{:0>2X}",
+            data.len(), address, record_type,
+        ));
+        for &byte in data {
+            try!(write!(self.output, "{:0>2X}", byte));
+            checksum = checksum.wrapping_add(byte);
+        }
+        try!(write!(self.output, "{:0>2X}\n", checksum.wrapping_neg()));
+
+        Ok(())
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        parse,
+        Error,
+        Writer,
+    };
+
+
+    #[test]
+    fn parses_data_records() {
+        let image = parse(
+            ":0400000001020304F2\n\
+             :020000040008F2\n\
+             :020010000506E3\n\
+             :00000001FF\n"
+        ).unwrap();
+
+        let segments = image.segments();
+        assert_eq!(segments.len(), 2);
+        assert_eq!(segments[0].address, 0x00000000);
+        assert_eq!(segments[0].data, [1, 2, 3, 4]);
+        assert_eq!(segments[1].address, 0x00080010);
+        assert_eq!(segments[1].data, [5, 6]);
+    }
+
+    #[test]
+    fn round_trips_across_64k_boundaries() {
+        let data: Vec<u8> = (0 .. 64).collect();
+
+        let mut writer = Writer::new(Vec::new());
+        writer.write(0x8FFE0, &data).unwrap();
+        let text = String::from_utf8(writer.finish().unwrap()).unwrap();
+
+        // The data records on either side of the boundary are preceded by
+        // their own extended linear address record.
+        assert!(text.contains(":020000040008F2\n"));
+        assert!(text.contains(":020000040009F1\n"));
+
+        let image = parse(&text).unwrap();
+        assert_eq!(image.segments().len(), 1);
+        assert_eq!(image.segments()[0].address, 0x8FFE0);
+        assert_eq!(image.segments()[0].data, data);
+    }
+
+    #[test]
+    fn rejects_bad_checksums() {
+        match parse(":0400000001020304F3\n:00000001FF\n") {
+            Err(Error::Checksum(1)) => (),
+            result => panic!("Unexpected result: {:?}", result.err()),
+        }
+    }
+
+    #[test]
+    fn rejects_unsupported_record_types() {
+        match parse(":00000006FA\n:00000001FF\n") {
+            Err(Error::InvalidRecord(1)) => (),
+            result => panic!("Unexpected result: {:?}", result.err()),
+        }
+    }
+
+    #[test]
+    fn rejects_truncated_input() {
+        match parse(":0400000001020304F2\n:04000400010203\n") {
+            Err(Error::InvalidRecord(2)) => (),
+            result => panic!("Unexpected result: {:?}", result.err()),
+        }
+        match parse(":0400000001020304F2\n") {
+            Err(Error::MissingEndOfFile) => (),
+            result => panic!("Unexpected result: {:?}", result.err()),
+        }
+    }
+}
@@ -0,0 +1,267 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs::File;
+use std::io;
+use std::io::prelude::*;
+use std::path::Path;
+
+use elf;
+use flash;
+use ihex;
+use result;
+use srec;
+
+
+/// An image that can be written to the flash memory. It consists of one or
+/// more segments, each of which is placed at its own address.
+pub struct Image {
+    segments: Vec<Segment>,
+}
+
+impl Image {
+    /// Creates an image from the given segments. Fails, if any of them
+    /// extends beyond the end of the address space.
+    pub fn new(segments: Vec<Segment>) -> Result<Self, Error> {
+        for segment in &segments {
+            try!(end_of(segment.address, segment.data.len() as u32));
+        }
+
+        Ok(Image {
+            segments: segments,
+        })
+    }
+
+    /// Creates an image without any segments. Use `add` to fill it.
+    pub fn empty() -> Self {
+        Image {
+            segments: Vec::new(),
+        }
+    }
+
+    /// Creates an image from a raw binary, which is placed at the start of the
+    /// flash memory.
+    pub fn from_binary(data: Vec<u8>) -> Result<Self, Error> {
+        Image::new(vec![
+            Segment {
+                address: flash::BASE_ADDR,
+                data   : data,
+            }
+        ])
+    }
+
+    /// Loads an image from a file. Intel HEX and S-record files are recognized
+    /// by their file extension, ELF files by their magic number. Everything
+    /// else is treated as a raw binary.
+    pub fn load<P: AsRef<Path>>(path: P) -> result::Result<Image> {
+        let path = path.as_ref();
+
+        let mut file = try!(File::open(path));
+
+        let mut data = Vec::new();
+        try!(file.read_to_end(&mut data));
+
+        let extension = path
+            .extension()
+            .and_then(|extension| extension.to_str())
+            .map(|extension| extension.to_lowercase())
+            .unwrap_or(String::new());
+
+        match extension.as_ref() {
+            "hex" | "ihex" =>
+                Ok(try!(ihex::parse(&try!(into_text(data))))),
+            "srec" | "s19" | "s28" | "s37" | "mot" =>
+                Ok(try!(srec::parse(&try!(into_text(data))))),
+
+            _ if elf::is_elf(&data) =>
+                Ok(try!(elf::parse(&data))),
+            _ =>
+                Ok(try!(Image::from_binary(data))),
+        }
+    }
+
+    /// Adds data at the given address. If the data directly follows the last
+    /// segment, it's appended to that segment. Otherwise a new segment is
+    /// started. Fails, if the data extends beyond the end of the address
+    /// space.
+    pub fn add(&mut self, address: u32, data: &[u8]) -> Result<(), Error> {
+        try!(end_of(address, data.len() as u32));
+
+        if let Some(segment) = self.segments.last_mut() {
+            let end = segment.address as u64 + segment.data.len() as u64;
+            if end == address as u64 {
+                segment.data.extend_from_slice(data);
+                return Ok(());
+            }
+        }
+
+        self.segments.push(Segment {
+            address: address,
+            data   : data.to_vec(),
+        });
+
+        Ok(())
+    }
+
+    pub fn segments(&self) -> &[Segment] {
+        &self.segments
+    }
+
+    /// Returns the little-endian word at the given address, if the image
+    /// contains all of its bytes.
+    pub fn read_word(&self, address: u32) -> Option<u32> {
+        self.segments
+            .iter()
+            .find(|segment| {
+                // Computed in 64 bits, so this can't overflow.
+                address >= segment.address
+                    && (address - segment.address) as u64 + 4
+                        <= segment.data.len() as u64
+            })
+            .map(|segment| {
+                let offset = (address - segment.address) as usize;
+                segment.data[offset .. offset + 4]
+                    .iter()
+                    .rev()
+                    .fold(0, |word, &byte| word << 8 | byte as u32)
+            })
+    }
+
+    /// Returns the number of bytes in all segments.
+    pub fn size(&self) -> u32 {
+        self.segments
+            .iter()
+            .fold(0, |size, segment| size + segment.data.len() as u32)
+    }
+
+    /// Splits the image into flash pages of the given size. Only pages that
+    /// contain data from at least one segment are returned, ordered by
+    /// address.
+    #[allow(clippy::iter_kv_map)]
+    pub fn pages(&self, page_size: u32) -> Vec<Page> {
+        let mut pages = BTreeMap::new();
+
+        for segment in &self.segments {
+            for (i, &byte) in segment.data.iter().enumerate() {
+                // This can't overflow, as `new` and `add` made sure that all
+                // segments lie within the address space.
+                let address      = segment.address + i as u32;
+                let page_address = address - address % page_size;
+                let offset       = (address - page_address) as usize;
+
+                let page = pages
+                    .entry(page_address)
+                    .or_insert_with(|| Page::new(page_address, page_size));
+
+                page.data[offset] = byte;
+                page.used[offset] = true;
+            }
+        }
+
+        pages.into_iter().map(|(_, page)| page).collect()
+    }
+}
+
+
+pub struct Segment {
+    pub address: u32,
+    pub data   : Vec<u8>,
+}
+
+
+/// A flash page's worth of data from an image.
+pub struct Page {
+    pub address: u32,
+    pub data   : Vec<u8>,
+
+    /// Marks which bytes of the page contain data from the image. All other
+    /// bytes are set to 0xff, the value of erased flash memory. When the page
+    /// is written, `Flash` keeps the current contents of those bytes instead.
+    pub used: Vec<bool>,
+}
+
+impl Page {
+    fn new(address: u32, size: u32) -> Self {
+        Page {
+            address: address,
+            data   : vec![0xff; size as usize],
+            used   : vec![false; size as usize],
+        }
+    }
+}
+
+
+#[derive(Debug)]
+pub enum Error {
+    /// The data at the given address and of the given length extends beyond
+    /// the end of the address space.
+    OutOfRange(u32, u32),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::OutOfRange(address, length) =>
+                write!(f,
+                    "Data at 0x{:0>8X} ({} bytes) extends beyond the end of \
+                    the address space",
+                    address, length,
+                ),
+        }
+    }
+}
+
+
+/// Returns the address right after the given range of memory, or
+/// `Error::OutOfRange`, if the range extends beyond the end of the address
+/// space. The result is 64 bits wide, as a range may end at the very end of
+/// the address space.
+fn end_of(address: u32, length: u32) -> Result<u64, Error> {
+    if length == 0 || address.checked_add(length - 1).is_some() {
+        Ok(address as u64 + length as u64)
+    }
+    else {
+        Err(Error::OutOfRange(address, length))
+    }
+}
+
+fn into_text(data: Vec<u8>) -> io::Result<String> {
+    String::from_utf8(data).map_err(|error|
+        io::Error::new(io::ErrorKind::InvalidData, error)
+    )
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        Error,
+        Image,
+        Segment,
+    };
+
+
+    #[test]
+    fn segments_may_end_at_the_end_of_the_address_space() {
+        let image = Image::new(vec![
+            Segment {
+                address: 0xFFFFFF00,
+                data   : vec![0; 0x100],
+            },
+        ]);
+        assert!(image.is_ok());
+
+        let mut image = Image::empty();
+        image.add(0xFFFFFFFC, &[1, 2, 3, 4]).unwrap();
+        image.add(0, &[5]).unwrap();
+        assert_eq!(image.segments().len(), 2);
+        assert_eq!(image.read_word(0xFFFFFFFC), Some(0x04030201));
+    }
+
+    #[test]
+    fn segments_beyond_the_end_of_the_address_space_are_rejected() {
+        match Image::empty().add(0xFFFFFFFD, &[0; 4]) {
+            Err(Error::OutOfRange(0xFFFFFFFD, 4)) => (),
+            result => panic!("Unexpected result: {:?}", result),
+        }
+    }
+}
@@ -0,0 +1,87 @@
+// Minimal support for writing JSON objects, for machine-readable output. Only
+// flat objects are supported, which is all we need for reporting events.
+
+
+use std::fmt;
+
+
+/// A flat JSON object. The fields are written in the order they were added.
+#[derive(Default)]
+pub struct Object {
+    fields: Vec<(&'static str, String)>,
+}
+
+impl Object {
+    pub fn new() -> Self {
+        Object {
+            fields: Vec::new(),
+        }
+    }
+
+    pub fn string(mut self, name: &'static str, value: &str) -> Self {
+        self.fields.push((name, quote(value)));
+        self
+    }
+
+    pub fn number(mut self, name: &'static str, value: u64) -> Self {
+        self.fields.push((name, value.to_string()));
+        self
+    }
+
+    pub fn float(mut self, name: &'static str, value: f64) -> Self {
+        self.fields.push((name, format!("{:.1}", value)));
+        self
+    }
+
+    pub fn boolean(mut self, name: &'static str, value: bool) -> Self {
+        self.fields.push((name, value.to_string()));
+        self
+    }
+
+    /// Adds the number, or `null`, if there is none.
+    pub fn optional(mut self, name: &'static str, value: Option<u64>) -> Self {
+        let value = value.map_or("null".to_string(), |value| value.to_string());
+        self.fields.push((name, value));
+        self
+    }
+}
+
+impl fmt::Display for Object {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        try!(write!(f, "{{"));
+
+        for (i, &(name, ref value)) in self.fields.iter().enumerate() {
+            if i > 0 {
+                try!(write!(f, ","));
+            }
+            try!(write!(f, "{}:{}", quote(name), value));
+        }
+
+        write!(f, "}}")
+    }
+}
+
+
+/// Returns the string as a JSON string literal. See RFC 8259, section 7.
+fn quote(s: &str) -> String {
+    let mut quoted = String::with_capacity(s.len() + 2);
+
+    quoted.push('"');
+    for c in s.chars() {
+        match c {
+            '"'  => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            '\t' => quoted.push_str("\\t"),
+
+            c if (c as u32) < 0x20 =>
+                quoted.push_str(&format!("\\u{:0>4x}", c as u32)),
+
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+
+    quoted
+}
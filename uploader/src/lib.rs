@@ -0,0 +1,54 @@
+//! Library for interfacing with the SAM-BA bootloader on a SAM3X8E
+//! microcontroller via USB.
+//!
+//! The SAM-BA protocol is implemented by `sam_ba::SamBa`, which works with any
+//! transport that implements `sam_ba::Transport`, not just serial ports. The
+//! flash controller and the programming logic are built on top of that.
+//!
+//! Several comments refer the the SAM3X/SAM3A data sheet, available at the
+//! following URI:
+//! http://www.atmel.com/Images/Atmel-11057-32-bit-Cortex-M3-Microcontroller-SAM3X-SAM3A_Datasheet.pdf
+
+
+/// Works like the standard library's `try!`, which is deprecated in favor of
+/// the `?` operator. This code base uses `try!` throughout, and defining it
+/// here keeps the deprecation warning from drowning out all others. It's
+/// exported for the binaries and tests, which use it via `#[macro_use]`.
+#[macro_export]
+macro_rules! try {
+    ($result:expr) => (
+        match $result {
+            Ok(value)  => value,
+            Err(error) => return Err(::std::convert::From::from(error)),
+        }
+    )
+}
+
+
+pub mod applet;
+pub mod chip_id;
+pub mod discovery;
+pub mod eefc;
+pub mod elf;
+pub mod flash;
+pub mod ihex;
+pub mod image;
+pub mod json;
+pub mod metadata;
+pub mod progress;
+pub mod ram;
+pub mod result;
+pub mod rstc;
+pub mod sam_ba;
+pub mod serial_port;
+pub mod simulator;
+pub mod srec;
+pub mod svd;
+pub mod utils;
+pub mod xml;
+pub mod xmodem;
+
+
+extern crate byteorder;
+extern crate libc;
+extern crate serial;
@@ -1,153 +1,1171 @@
-/// Program for interfacing with the SAM-BA bootloader on a SAM3X8E
-/// microcontroller via USB.
-///
-/// Several comments refer the the SAM3X/SAM3A data sheet, available at the
-/// following URI:
-/// http://www.atmel.com/Images/Atmel-11057-32-bit-Cortex-M3-Microcontroller-SAM3X-SAM3A_Datasheet.pdf
-
-
-mod eefc;
-mod result;
-mod sam_ba;
-mod serial_port;
-mod utils;
+//! Command-line interface to the uploader library. See `lib.rs` for details.
 
 
-extern crate byteorder;
 extern crate serial;
+#[macro_use]
+extern crate upload;
 
 
 use std::env;
+use std::fmt;
 use std::fs::File;
+use std::io::prelude::*;
+use std::path::Path;
+use std::process;
+use std::result;
+use std::thread;
+use std::time::Duration;
 
-use byteorder::{
-    LittleEndian,
-    ReadBytesExt,
-};
-
-use eefc::{
+use upload::applet::Applet;
+use upload::chip_id::ChipId;
+use upload::discovery;
+use upload::eefc::{
+    ClearGpnvmBit,
     Eefc,
-    ErasePageAndWritePage,
+    GetGpnvmBit,
     GpnvmNumber,
-    Page,
     SetGpnvmBit,
 };
-use sam_ba::SamBa;
+use upload::flash;
+use upload::flash::Flash;
+use upload::ihex;
+use upload::image::Image;
+use upload::json;
+use upload::metadata;
+use upload::metadata::Metadata;
+use upload::progress;
+use upload::ram;
+use upload::result::Result;
+use upload::rstc;
+use upload::sam_ba;
+use upload::serial_port;
+use upload::svd;
+use upload::utils::parse_u32;
+
+
+type SamBa = sam_ba::SamBa<serial::SystemPort>;
+
+
+/// Where `./compile` builds the applet. See `read_flash`.
+const DEFAULT_APPLET_PATH: &str = concat!(
+    env!("CARGO_MANIFEST_DIR"),
+    "/../applet/target/target/release/applet",
+);
 
 
 fn main() {
     let mut args = env::args();
-    args.next().expect("Expected program name as first entry in args");
+    args.next();
+
+    // The device path is optional. If it's left out, we look for a connected
+    // device ourselves. Relative paths, like `ttyACM0` in `/dev`, are
+    // recognized by the file existing, as commands don't contain a '/'.
+    let first = expect_argument(args.next(), "Expected command argument");
+    let is_path = first.contains('/') || Path::new(&first).exists();
+    let (device_path, command) = if is_path {
+        let command = expect_argument(args.next(), "Expected command argument");
+        (Some(first), command)
+    }
+    else {
+        (None, first)
+    };
+
+    if command == "list" {
+        list_devices();
+        return;
+    }
+
+    // Fills in the metadata block of a freshly linked program. This is run by
+    // `./compile` and doesn't need a device.
+    if command == "stamp" {
+        let path = expect_argument(
+            args.next(),
+            "Expected file path argument",
+        );
+        let version = expect_argument(
+            args.next(),
+            "Expected version argument",
+        );
+        let git_hash = args.next().unwrap_or_default();
+
+        let metadata = exit_on_error(
+            metadata::stamp(&path, &version, &git_hash),
+            "Failed to stamp image",
+        );
+
+        print!(
+            "Stamped {} ({} bytes, CRC32 0x{:0>8X})\n",
+            path, metadata.image_length, metadata.crc32,
+        );
+        return;
+    }
+
+    let discover    = device_path.is_none();
+    let device_path = device_path.unwrap_or_else(find_device);
+
+    // Resetting the device into the bootloader erases the whole flash memory,
+    // so we can't do that, if the caller wants to keep the pages that aren't
+    // part of the image. Writing only changed pages requires the old contents
+    // of the flash memory, too.
+    let no_erase = env::args()
+        .any(|arg| arg == "--no-erase" || arg == "--diff");
+
+    // The touch asserts the ERASE pin, which also clears the lock bits and the
+    // GPNVM bits. If the device is already running the bootloader and has
+    // locked regions, skip it. `Flash::erase` leaves locked regions alone.
+    //
+    // Finding out means probing the port for the SAM-BA monitor, which sends
+    // a few commands to whatever is running on the device. That's why the
+    // probe is skipped along with the touch, if `--no-erase` or `--diff` is
+    // passed.
+    let touch = match command.as_ref() {
+        "erase"       => true,
+        "upload-file" => !no_erase,
+        _             => false,
+    };
 
-    let device_path = args.next().expect("Expected device path argument");
-    let command     = args.next().expect("Expected command argument");
+    if touch && !has_locked_regions(&device_path) {
+        exit_on_io_error(
+            serial_port::touch(&device_path),
+            "Failed to reset device into bootloader",
+        );
 
-    let port = serial_port::init(&device_path)
-        .expect("Failed to initialize serial port");
+        // Give the microcontroller some time to erase itself and start the
+        // bootloader.
+        thread::sleep(Duration::from_millis(1000));
+    }
+
+    // If the device was reset through the native port, it comes back as the
+    // bootloader, possibly with a different device file.
+    let device_path = if discover { find_device() } else { device_path };
+
+    let port = exit_on_io_error(
+        serial_port::init(&device_path),
+        "Failed to initialize serial port",
+    );
 
     let mut sam_ba = SamBa::new(port);
-    let     eefc_0 = Eefc::eefc_0();
+    if let Some(retries) = retries() {
+        sam_ba.set_retries(retries);
+    }
+    if raw_transfers(&device_path) {
+        sam_ba.set_raw_transfers(true);
+    }
 
-    sam_ba.set_normal_mode().expect("Failed to set normal mode");
+    exit_on_error(
+        sam_ba.set_normal_mode(),
+        "Failed to connect to SAM-BA monitor",
+    );
+
+    if json_output() {
+        let version = exit_on_error(
+            sam_ba.display_version(),
+            "Failed to retrieve version",
+        );
+
+        emit(event("connected")
+            .string("device", &device_path)
+            .string("version", version.trim())
+        );
+    }
 
     match command.as_ref() {
         "version" => {
-            let version = sam_ba.display_version()
-                .expect("Failed to retrieve version");
+            let version = exit_on_error(
+                sam_ba.display_version(),
+                "Failed to retrieve version",
+            );
 
             print!("{}", version)
         },
 
         "upload-file" => {
-            let path = args.next().expect("Expected file path argument");
-
-            let mut file = File::open(&path).expect("Failed to create file");
-
-            let file_size = file
-                .metadata()
-                .expect("Failed to read file metadata")
-                .len();
-
-            if file_size > 256 * 1024 {
-                // The SAM3X8E has 512 KiB of memory, but it is divided into
-                // two planes of 256 KiB each. The code below doesn't really
-                // take that into account and would silently fail in various
-                // ways, if the file were bigger than 256 KiB.
-                // See sections 7.2.3 and chapter 18 in the data sheet for more
-                // information.
-                panic!("{} {}",
-                    "The file is too big. This program only supports file",
-                    "sizes up to 256 KiB."
-                );
+            let path = expect_argument(
+                args.next(),
+                "Expected file path argument",
+            );
+
+            let mut verify = false;
+            let mut diff   = false;
+            let mut force  = false;
+            let mut reset  = true;
+            let mut unlock = false;
+            for option in args {
+                match option.as_ref() {
+                    "--verify"    => verify = true,
+                    "--diff"      => diff   = true,
+                    "--force"     => force  = true,
+                    "--no-reset"  => reset  = false,
+                    "--unlock"    => unlock = true,
+                    "--no-erase"  => (),
+                    "--json"      => (),
+
+                    "--raw-transfers" => (),
+
+                    "--no-applet" => (),
+
+                    option if option.starts_with("--applet=")  => (),
+                    option if option.starts_with("--retries=") => (),
+
+                    _ => fail("usage", &format!("Unknown option: {}", option)),
+                }
             }
 
-            // Given the check above, we know that the following cast is safe.
-            let file_size = file_size as u32;
-
-            // Base address of the internal flash memory. See data sheet,
-            // section 7.1.
-            let flash_base_addr = 0x00080000;
-
-            // Pages consist of 256 bytes each. A word is 4 bytes long, as ARM
-            // is a 32-bit architecture.
-            // See sections 7.2.3.1 and 10.4.5 in the data sheet.
-            let word_size_bytes = 4;
-            let page_size_bytes = 256;
-            let page_size_words = page_size_bytes / word_size_bytes;
-
-            let number_of_pages =
-                (file_size + page_size_bytes - 1) / page_size_bytes;
-
-            // This sets the number of wait states for flash read/write
-            // operations to 6. See data sheet, section 18.5.1. According to
-            // the errata section, this is required. Otherwise data written can
-            // be corrupted. See section 49.1.1.1.
-            // Please note that I wasn't able to verify that this really is
-            // necessary. However, I was testing with a binary that wasn't
-            // optimized. It is probable that flipping some bits here or there
-            // wouldn't inhibit the functioning of that binary.
-            sam_ba.write_word(0x400E0A00, 0x00000600)
-                .expect("Failed to write wait state");
-
-            for page in 0 .. number_of_pages {
-                for i in 0 .. page_size_words {
-                    let offset  = page * page_size_bytes + i * word_size_bytes;
-                    let address = flash_base_addr + offset;
-
-                    let word = if offset < file_size {
-                        file.read_u32::<LittleEndian>()
-                            .expect("Failed to read from file")
-                    }
-                    else {
-                        0
-                    };
+            let flash = read_flash(&mut sam_ba);
+            let image = read_image(&path, &flash);
+
+            if json_output() {
+                emit_chip(&mut sam_ba, &flash);
+            }
 
-                    sam_ba.write_word(address, word)
-                        .expect("Failed to write word");
+            print_image_metadata(&image);
+
+            if !force {
+                exit_on_error(
+                    flash.check(&image),
+                    "Refusing to write image (pass --force to write it anyway)",
+                );
+            }
+
+            if unlock {
+                for segment in image.segments() {
+                    exit_on_error(
+                        flash.unlock(
+                            &mut sam_ba,
+                            segment.address, segment.data.len() as u32,
+                        ),
+                        "Failed to unlock flash",
+                    );
                 }
+            }
+            else {
+                exit_on_error(
+                    flash.check_locks(&mut sam_ba, &image),
+                    "Refusing to write image (pass --unlock to unlock it)",
+                );
+            }
+
+            if !no_erase {
+                exit_on_error(
+                    flash.erase(&mut sam_ba),
+                    "Failed to erase flash",
+                );
+            }
 
-                eefc_0
-                    .execute_command::<ErasePageAndWritePage, _>(
-                        &mut sam_ba,
-                        Page(page as u16),
-                    )
-                    .expect("Failed erase page and write page");
+            let mut report = progress::Report::new("Writing", json_output());
+
+            let (pages_written, pages_skipped) = if diff {
+                let written = exit_on_error(
+                    flash.write_changed(&mut sam_ba, &image, |progress|
+                        report.report(progress)
+                    ),
+                    "Failed to write image to flash",
+                );
+
+                (written.pages_written, written.pages_skipped)
+            }
+            else {
+                let number_of_pages = exit_on_error(
+                    flash.write(&mut sam_ba, &image, |progress|
+                        report.report(progress)
+                    ),
+                    "Failed to write image to flash",
+                );
+
+                (number_of_pages, 0)
+            };
+
+            if json_output() {
+                emit(event("written")
+                    .number("bytes", image.size() as u64)
+                    .number("pages", pages_written as u64)
+                    .number("skipped", pages_skipped as u64)
+                );
+            }
+            else if diff {
+                print!(
+                    "Wrote {} page(s), skipped {} unchanged page(s)\n",
+                    pages_written, pages_skipped,
+                );
+            }
+            else {
+                print!(
+                    "Wrote {} bytes ({} pages)\n",
+                    image.size(), pages_written,
+                );
             }
 
-            eefc_0
-                .execute_command::<SetGpnvmBit, _>(
+            exit_on_error(
+                Eefc::eefc_0().execute_command::<SetGpnvmBit, _, _>(
                     &mut sam_ba,
                     GpnvmNumber::BootModeSelection,
-                )
-                .expect("Failed to set GPNVM bit");
+                ),
+                "Failed to set GPNVM bit",
+            );
+
+            if verify {
+                verify_image(&mut sam_ba, &flash, &image);
+            }
+
+            // Start the new program right away, instead of waiting for
+            // someone to press the reset button.
+            if reset {
+                reset_device(&mut sam_ba);
+            }
+        },
+
+        "reset" => {
+            reset_device(&mut sam_ba);
+        },
+
+        "erase" => {
+            let flash = read_flash(&mut sam_ba);
+
+            exit_on_error(flash.erase(&mut sam_ba), "Failed to erase flash");
+
+            print!("Erased flash\n");
+        },
+
+        "gpnvm" => {
+            let eefc_0 = Eefc::eefc_0();
+
+            let (bit, set) = match args.next() {
+                None => {
+                    print_gpnvm_bits(&mut sam_ba);
+                    return;
+                },
+
+                Some(ref setting) if setting == "boot-mode" => {
+                    let mode = expect_argument(
+                        args.next(),
+                        "Expected boot mode (\"rom\" or \"flash\")",
+                    );
+
+                    match mode.as_ref() {
+                        "rom"   => (GpnvmNumber::BootModeSelection, false),
+                        "flash" => (GpnvmNumber::BootModeSelection, true),
+                        _       => fail(
+                            "usage",
+                            &format!("Unknown boot mode: {}", mode),
+                        ),
+                    }
+                },
+
+                Some(ref setting) if setting == "flash-selection" => {
+                    let plane = expect_argument(
+                        args.next(),
+                        "Expected flash plane (\"0\" or \"1\")",
+                    );
+
+                    match plane.as_ref() {
+                        "0" => (GpnvmNumber::FlashSelection, false),
+                        "1" => (GpnvmNumber::FlashSelection, true),
+                        _   => fail(
+                            "usage",
+                            &format!("Unknown flash plane: {}", plane),
+                        ),
+                    }
+                },
+
+                Some(ref setting) if setting == "security" => {
+                    // Once the security bit is set, the flash memory can no
+                    // longer be accessed through SAM-BA, and only erasing the
+                    // whole chip through the ERASE pin clears it again.
+                    if args.next() != Some("--confirm-security".to_string()) {
+                        fail("usage",
+                            "Setting the security bit is irreversible. Pass \
+                            --confirm-security, if you really want to do it."
+                        );
+                    }
+
+                    (GpnvmNumber::Security, true)
+                },
+
+                Some(setting) =>
+                    fail(
+                        "usage",
+                        &format!("Unknown GPNVM setting: {}", setting),
+                    ),
+            };
+
+            let result = if set {
+                eefc_0.execute_command::<SetGpnvmBit, _, _>(&mut sam_ba, bit)
+            }
+            else {
+                eefc_0.execute_command::<ClearGpnvmBit, _, _>(&mut sam_ba, bit)
+            };
+            exit_on_error(result, "Failed to change GPNVM bit");
+
+            print_gpnvm_bits(&mut sam_ba);
+        },
+
+        "lock" | "unlock" => {
+            let address = parse_argument(args.next(), "address");
+            let length  = parse_argument(args.next(), "length");
+
+            let flash = exit_on_error(
+                Flash::read(&mut sam_ba),
+                "Failed to read flash descriptor",
+            );
+
+            let (result, done) = if command == "lock" {
+                (flash.lock(&mut sam_ba, address, length), "Locked")
+            }
+            else {
+                (flash.unlock(&mut sam_ba, address, length), "Unlocked")
+            };
+            let number_of_regions = exit_on_error(
+                result,
+                &format!("Failed to {} flash", command),
+            );
+
+            print!("{} {} lock region(s)\n", done, number_of_regions);
+            print_locked_regions(&mut sam_ba, &flash);
+        },
+
+        "peek" => {
+            let width   = parse_width(args.next());
+            let address = parse_argument(args.next(), "address");
+
+            check_alignment(address, width);
+
+            let value = match width {
+                8  => sam_ba.read_byte(address).map(|value| value as u32),
+                16 => sam_ba.read_half_word(address).map(|value| value as u32),
+                _  => sam_ba.read_word(address),
+            };
+            let value = exit_on_error(value, "Failed to read memory");
+
+            print!(
+                "0x{:0>8X}: 0x{:0>width$X}\n",
+                address, value, width = width as usize / 4,
+            );
+        },
+
+        "poke" => {
+            let width   = parse_width(args.next());
+            let address = parse_argument(args.next(), "address");
+            let value   = parse_argument(args.next(), "value");
+
+            check_alignment(address, width);
+            if width < 32 && value >> width != 0 {
+                fail("usage", &format!(
+                    "Value 0x{:X} doesn't fit into {} bits",
+                    value, width,
+                ));
+            }
+
+            let result = match width {
+                8  => sam_ba.write_byte(address, value as u8),
+                16 => sam_ba.write_half_word(address, value as u16),
+                _  => sam_ba.write_word(address, value),
+            };
+            exit_on_error(result, "Failed to write memory");
 
             print!(
-                "Wrote {} bytes ({} pages)\n",
-                file_size, number_of_pages,
+                "0x{:0>8X} <- 0x{:0>width$X}\n",
+                address, value, width = width as usize / 4,
             );
         },
 
+        "regs" => {
+            let name = expect_argument(
+                args.next(),
+                "Expected peripheral argument",
+            );
+
+            // The SVD file isn't part of this repository. Atmel publishes it
+            // as part of its device packs.
+            let path = expect_argument(
+                env::args()
+                    .find(|arg| arg.starts_with("--svd="))
+                    .map(|arg| arg["--svd=".len() ..].to_string()),
+                "Expected --svd=<path>, the SVD file of the SAM3X8E, which is \
+                part of Atmel's device packs",
+            );
+
+            let device = exit_on_error(
+                svd::Device::load(&path),
+                &format!("Failed to load {}", path),
+            );
+
+            match device.peripheral(&name) {
+                Some(peripheral) =>
+                    print_registers(&mut sam_ba, peripheral),
+                None => {
+                    let names: Vec<_> = device.peripherals
+                        .iter()
+                        .map(|peripheral| peripheral.name.as_ref())
+                        .collect();
+
+                    fail("usage", &format!(
+                        "Unknown peripheral: {}\nAvailable peripherals: {}",
+                        name, names.join(", "),
+                    ));
+                },
+            }
+        },
+
+        "whoami" => {
+            let flash = read_flash(&mut sam_ba);
+
+            let metadata = exit_on_error(
+                Metadata::read(&mut sam_ba, flash::BASE_ADDR),
+                "Failed to read metadata",
+            );
+            let metadata = exit_on_error(
+                metadata.ok_or(metadata::Error::NotFound.into()),
+                "Failed to identify program in flash memory",
+            );
+
+            // Don't trust the length blindly. If the block is damaged, it
+            // could make us read far beyond the flash memory.
+            let checksum = if metadata.is_stamped()
+                && flash.contains(metadata.image_address, metadata.image_length)
+            {
+                exit_on_error(
+                    metadata.read_checksum(&mut sam_ba, flash.applet()),
+                    "Failed to read program from flash memory",
+                )
+            }
+            else {
+                None
+            };
+
+            print_metadata("flash memory", &metadata, checksum);
+        },
+
+        "uid" => {
+            let unique_id = exit_on_error(
+                Eefc::eefc_0().read_unique_id(&mut sam_ba, flash::BASE_ADDR),
+                "Failed to read unique identifier",
+            );
+
+            for byte in unique_id.iter() {
+                print!("{:0>2x}", byte);
+            }
+            print!("\n");
+        },
+
+        "info" => {
+            if json_output() {
+                let flash = exit_on_error(
+                    Flash::read(&mut sam_ba),
+                    "Failed to read flash descriptor",
+                );
+
+                emit_chip(&mut sam_ba, &flash);
+            }
+            else {
+                print_info(&mut sam_ba);
+            }
+        },
+
+        "verify" => {
+            let path = expect_argument(
+                args.next(),
+                "Expected file path argument",
+            );
+
+            let flash = read_flash(&mut sam_ba);
+            let image = read_image(&path, &flash);
+
+            verify_image(&mut sam_ba, &flash, &image);
+        },
+
+        "run-ram" => {
+            let path = expect_argument(
+                args.next(),
+                "Expected file path argument",
+            );
+
+            let image = exit_on_error(
+                Image::load(&path),
+                "Failed to load image",
+            );
+
+            exit_on_error(
+                ram::run(&mut sam_ba, &image),
+                "Failed to run image from SRAM",
+            );
+
+            print!("Started {} bytes from SRAM\n", image.size());
+        },
+
+        "dump" => {
+            let address = parse_argument(args.next(), "address");
+            let length  = parse_argument(args.next(), "length");
+            let path    = expect_argument(
+                args.next(),
+                "Expected output file argument",
+            );
+
+            let mut ihex = false;
+            for option in args {
+                match option.as_ref() {
+                    "--ihex" => ihex = true,
+                    "--json" => (),
+
+                    "--raw-transfers" => (),
+
+                    option if option.starts_with("--retries=") => (),
+
+                    _ => fail("usage", &format!("Unknown option: {}", option)),
+                }
+            }
+
+            let file = exit_on_io_error(
+                File::create(&path),
+                "Failed to create file",
+            );
+
+            if ihex {
+                let mut writer = ihex::Writer::new(file);
+
+                exit_on_error(
+                    sam_ba.dump(address, length, |address, data| {
+                        try!(writer.write(address, data));
+                        Ok(())
+                    }),
+                    "Failed to dump memory",
+                );
+
+                exit_on_io_error(writer.finish(), "Failed to write to file");
+            }
+            else {
+                let mut file = file;
+
+                exit_on_error(
+                    sam_ba.dump(address, length, |_, data| {
+                        try!(file.write_all(data));
+                        Ok(())
+                    }),
+                    "Failed to dump memory",
+                );
+            }
+
+            print!("Read {} bytes from 0x{:0>8X}\n", length, address);
+        },
+
         _ =>
-            print!("Unknown command: {}\n", command),
+            fail("usage", &format!("Unknown command: {}", command)),
+    }
+}
+
+
+fn list_devices() {
+    let devices = exit_on_io_error(
+        discovery::find(),
+        "Failed to look for devices",
+    );
+
+    if devices.is_empty() {
+        print!("No devices found\n");
+    }
+
+    for device in devices {
+        print!("{}: {}, serial number {}\n",
+            device.path,
+            device.kind.description(),
+            device.serial.as_ref().map_or("unknown", |serial| &serial[..]),
+        );
+    }
+}
+
+/// Returns the path of the only connected device. Exits, if there is no such
+/// device, or more than one.
+fn find_device() -> String {
+    let mut devices = exit_on_io_error(
+        discovery::find(),
+        "Failed to look for devices",
+    );
+
+    match devices.len() {
+        1 => devices.remove(0).path,
+
+        0 =>
+            fail(
+                "no_device",
+                "No device found. Is the Arduino Due connected?",
+            ),
+
+        _ => {
+            let mut message = String::from(
+                "Multiple devices found. Please pass the device path:",
+            );
+            for device in devices {
+                message.push_str(&format!("\n  {}: {}",
+                    device.path, device.kind.description(),
+                ));
+            }
+            fail("multiple_devices", &message)
+        },
+    }
+}
+
+/// Returns whether the device is running the SAM-BA monitor and has locked
+/// flash regions. If the monitor doesn't reply, the device is probably running
+/// a program, and there's no way to tell.
+///
+/// The probe sends `CAN CAN #`, `N#` and read commands at 115200 baud. If a
+/// program is running, it receives them as input. See `main`.
+fn has_locked_regions(device_path: &str) -> bool {
+    let port = match serial_port::init(device_path) {
+        Ok(port) => port,
+        Err(_)   => return false,
+    };
+
+    // A program wouldn't reply, no matter how often we ask.
+    let mut sam_ba = SamBa::new(port);
+    sam_ba.set_retries(0);
+
+    if sam_ba.set_normal_mode().is_err() {
+        return false;
+    }
+
+    Flash::read(&mut sam_ba)
+        .and_then(|flash| flash.lock_regions(&mut sam_ba))
+        .map(|regions| regions.iter().any(|region| region.locked))
+        .unwrap_or(false)
+}
+
+/// Reads the flash descriptors and loads the applet (see `applet` module). The
+/// applet is loaded from `--applet=<path>`, if passed, or otherwise from where
+/// `./compile` builds it, if it's there. `--no-applet` disables it.
+fn read_flash(sam_ba: &mut SamBa) -> Flash {
+    let mut flash = exit_on_error(
+        Flash::read(sam_ba),
+        "Failed to read flash descriptor",
+    );
+
+    if env::args().any(|arg| arg == "--no-applet") {
+        return flash;
+    }
+
+    let applet_path = env::args()
+        .find(|arg| arg.starts_with("--applet="))
+        .map(|arg| arg["--applet=".len() ..].to_string());
+    let applet_path = match applet_path {
+        Some(path) =>
+            Some(path),
+        None if Path::new(DEFAULT_APPLET_PATH).exists() =>
+            Some(DEFAULT_APPLET_PATH.to_string()),
+        None =>
+            None,
+    };
+
+    if let Some(path) = applet_path {
+        flash.use_applet(exit_on_error(
+            Applet::load_file(sam_ba, &path),
+            "Failed to load applet",
+        ));
+    }
+
+    flash
+}
+
+/// Reads an image from a file and makes sure it fits into the flash memory.
+fn read_image(path: &str, flash: &Flash) -> Image {
+    let image = exit_on_error(Image::load(path), "Failed to load image");
+    exit_on_error(flash.check_bounds(&image), "Image doesn't fit");
+
+    image
+}
+
+/// Prints the metadata of the image, if it has any. See `metadata` module.
+fn print_image_metadata(image: &Image) {
+    let (address, data) = match metadata::contents(image) {
+        Some(contents) => contents,
+        None           => return,
+    };
+
+    if let Some(metadata) = Metadata::find(&data, address) {
+        let checksum = metadata.checksum(&data);
+        print_metadata("image", &metadata, checksum);
+    }
+}
+
+/// Prints the metadata and warns, if the CRC32 of the data it describes
+/// doesn't match the one it contains. That means the data was changed after
+/// it was stamped, or, in the flash memory, only partially written.
+fn print_metadata(source: &str, metadata: &Metadata, checksum: Option<u32>) {
+    let crc_ok = checksum == Some(metadata.crc32);
+
+    if json_output() {
+        emit(event("metadata")
+            .string("source", source)
+            .boolean("stamped", metadata.is_stamped())
+            .string("version", &metadata.version)
+            .string("git_hash", &metadata.git_hash)
+            .number("timestamp", metadata.timestamp as u64)
+            .number("image_length", metadata.image_length as u64)
+            .number("crc32", metadata.crc32 as u64)
+            .optional("actual_crc32", checksum.map(|crc| crc as u64))
+            .boolean("crc_ok", crc_ok)
+        );
+        return;
+    }
+
+    if !metadata.is_stamped() {
+        print!(
+            "Metadata:        not filled in (was the {} built by ./compile?)\n",
+            source,
+        );
+        return;
+    }
+
+    print!("Version:         {}\n", metadata.version);
+    print!("Git hash:        {}\n", metadata.git_hash);
+    print!("Built:           {}\n",
+        metadata::format_timestamp(metadata.timestamp),
+    );
+    print!("Length:          {} bytes\n", metadata.image_length);
+    print!("CRC32:           0x{:0>8X}\n", metadata.crc32);
+
+    if crc_ok {
+        return;
+    }
+
+    match checksum {
+        Some(checksum) =>
+            print!(
+                "Warning: CRC32 of the {} is 0x{:0>8X}, which doesn't match \
+                its metadata\n",
+                source, checksum,
+            ),
+        None =>
+            print!(
+                "Warning: The {} is shorter than its metadata says\n",
+                source,
+            ),
+    }
+}
+
+fn print_gpnvm_bits(sam_ba: &mut SamBa) {
+    let bits = exit_on_error(
+        Eefc::eefc_0().execute_command::<GetGpnvmBit, _, _>(sam_ba, ()),
+        "Failed to get GPNVM bits",
+    );
+
+    let is_set = |bit: GpnvmNumber| bits & 1 << bit as u32 != 0;
+
+    print!("Security:        {}\n",
+        if is_set(GpnvmNumber::Security) { "enabled" } else { "disabled" }
+    );
+    print!("Boot mode:       {}\n",
+        if is_set(GpnvmNumber::BootModeSelection) { "flash" } else { "ROM" }
+    );
+    print!("Flash selection: {}\n",
+        if is_set(GpnvmNumber::FlashSelection) { "flash 1" } else { "flash 0" }
+    );
+}
+
+fn print_info(sam_ba: &mut SamBa) {
+    let chip_id = exit_on_error(
+        ChipId::read(sam_ba),
+        "Failed to read chip ID",
+    );
+
+    print!("Chip ID:         0x{:0>8X}\n", chip_id.cidr);
+    if chip_id.has_extension() {
+        print!("Extended ID:     0x{:0>8X}\n", chip_id.exid);
+    }
+    print!("Version:         {}\n", chip_id.version());
+    print!("Processor:       {}\n", chip_id.processor());
+    print!("Architecture:    {} (0x{:0>2X})\n",
+        chip_id.architecture_name(), chip_id.architecture(),
+    );
+    print!("Memory type:     {}\n", chip_id.memory_type());
+    print!("SRAM size:       {} KiB\n", chip_id.sram_size() / 1024);
+    for size in chip_id.flash_size().iter().chain(&chip_id.flash_size_2()) {
+        print!("Flash size:      {} KiB\n", size / 1024);
+    }
+
+    let flash = exit_on_error(
+        Flash::read(sam_ba),
+        "Failed to read flash descriptor",
+    );
+
+    for (i, bank) in flash.banks().iter().enumerate() {
+        let descriptor = &bank.descriptor;
+
+        print!("\n");
+        print!("Flash bank {}:    0x{:0>8X}\n", i, bank.address);
+        print!("  ID:            0x{:0>8X}\n", descriptor.id);
+        print!("  Size:          {} KiB\n", descriptor.size / 1024);
+        print!("  Page size:     {} bytes\n", descriptor.page_size);
+        print!("  Planes:        {}\n", descriptor.planes.len());
+        print!("  Lock regions:  {}\n", descriptor.lock_regions.len());
     }
+
+    print!("\n");
+    print_locked_regions(sam_ba, &flash);
+
+    print!("\n");
+    print_gpnvm_bits(sam_ba);
+}
+
+fn print_locked_regions(sam_ba: &mut SamBa, flash: &Flash) {
+    let lock_regions = exit_on_error(
+        flash.lock_regions(sam_ba),
+        "Failed to get lock bits",
+    );
+
+    let mut locked = 0;
+    for region in lock_regions.iter().filter(|region| region.locked) {
+        print!("Locked:          0x{:0>8X}-0x{:0>8X} (bank {})\n",
+            region.address, region.address + region.size - 1, region.bank,
+        );
+        locked += 1;
+    }
+    if locked == 0 {
+        print!("Locked:          none\n");
+    }
+}
+
+fn verify_image(sam_ba: &mut SamBa, flash: &Flash, image: &Image) {
+    let mut report = progress::Report::new("Verifying", json_output());
+
+    let verification = exit_on_error(
+        flash.verify(sam_ba, image, |progress| report.report(progress)),
+        "Failed to verify image",
+    );
+
+    if json_output() {
+        emit(event("verify")
+            .boolean("ok", verification.first_mismatch.is_none())
+            .number("bytes", image.size() as u64)
+            .optional(
+                "first_mismatch",
+                verification.first_mismatch.map(|address| address as u64),
+            )
+            .number("mismatched_pages", verification.mismatched_pages as u64)
+        );
+    }
+
+    match verification.first_mismatch {
+        None => {
+            if !json_output() {
+                print!("Verified {} bytes\n", image.size());
+            }
+        },
+
+        Some(address) => {
+            if !json_output() {
+                print!(
+                    "Verification failed: First mismatch at 0x{:0>8X}, {} \
+                    mismatching page(s)\n",
+                    address, verification.mismatched_pages,
+                );
+            }
+            process::exit(1);
+        },
+    }
+}
+
+fn reset_device(sam_ba: &mut SamBa) {
+    exit_on_error(rstc::reset(sam_ba), "Failed to reset");
+
+    if json_output() {
+        emit(event("reset"));
+    }
+    else {
+        print!("Reset device\n");
+    }
+}
+
+/// Returns the value, or prints the error and exits. This is used for errors
+/// that can happen during normal operation, like trying to write a locked
+/// flash region, where a panic would be confusing.
+fn exit_on_error<T>(result: Result<T>, message: &str) -> T {
+    match result {
+        Ok(value)  => value,
+        Err(error) =>
+            fail(error.code(), &format!("{}: {}", message, error)),
+    }
+}
+
+/// Like `exit_on_error`, for errors from the serial port, the file system and
+/// other places outside of this crate, which don't come with an error code.
+fn exit_on_io_error<T, E: fmt::Display>(
+    result : result::Result<T, E>,
+    message: &str,
+) -> T {
+    match result {
+        Ok(value)  => value,
+        Err(error) => fail("io", &format!("{}: {}", message, error)),
+    }
+}
+
+/// Returns the argument, or exits with the message, if it's missing.
+fn expect_argument(argument: Option<String>, message: &str) -> String {
+    match argument {
+        Some(argument) => argument,
+        None           => fail("usage", message),
+    }
+}
+
+/// Parses a numeric argument, like an address or a length, or exits, if it's
+/// missing or not a number.
+fn parse_argument(argument: Option<String>, name: &str) -> u32 {
+    let argument = expect_argument(
+        argument,
+        &format!("Expected {} argument", name),
+    );
+
+    match parse_u32(&argument) {
+        Ok(value) => value,
+        Err(_)    => fail("usage", &format!("Failed to parse {}", name)),
+    }
+}
+
+/// Prints the message, or emits it as an error event with `--json`, and exits.
+/// The code tells scripts what went wrong, without parsing the message.
+fn fail(code: &str, message: &str) -> ! {
+    if json_output() {
+        emit(event("error")
+            .string("code", code)
+            .string("message", message)
+        );
+    }
+    else {
+        print!("{}\n", message);
+    }
+    process::exit(1);
+}
+
+/// Returns whether to print JSON events, one per line, instead of output for
+/// humans. This is meant for scripts and CI systems. Only the commands that
+/// are commonly used from scripts support it.
+fn json_output() -> bool {
+    env::args().any(|arg| arg == "--json")
+}
+
+/// Reads all registers of the peripheral and prints them, field by field.
+fn print_registers(sam_ba: &mut SamBa, peripheral: &svd::Peripheral) {
+    for register in &peripheral.registers {
+        let address = peripheral.base_address + register.offset;
+
+        if !register.readable {
+            print!(
+                "{} (0x{:0>8X}): not read (write-only or changes when read)\n",
+                register.name, address,
+            );
+            continue;
+        }
+
+        let value = match register.size {
+            8  => sam_ba.read_byte(address).map(|value| value as u32),
+            16 => sam_ba.read_half_word(address).map(|value| value as u32),
+            _  => sam_ba.read_word(address),
+        };
+        let value = exit_on_error(
+            value,
+            &format!("Failed to read {}", register.name),
+        );
+
+        print!(
+            "{} (0x{:0>8X}) = 0x{:0>width$X}\n",
+            register.name, address, value,
+            width = register.size as usize / 4,
+        );
+
+        let name_width = register.fields
+            .iter()
+            .map(|field| field.name.len())
+            .max()
+            .unwrap_or(0);
+
+        for field in &register.fields {
+            let field_value = field.extract(value);
+            let name        = format!("{}.{}", register.name, field.name);
+
+            print!(
+                "    {:<width$} = ",
+                name, width = register.name.len() + 1 + name_width,
+            );
+            match field.name_of(field_value) {
+                Some(value_name) =>
+                    print!("{} (0x{:X})\n", value_name, field_value),
+                None =>
+                    print!("0x{:X}\n", field_value),
+            }
+        }
+    }
+}
+
+/// Parses the access width argument of `peek` and `poke`, in bits.
+fn parse_width(width: Option<String>) -> u32 {
+    let width = expect_argument(width, "Expected width argument (8, 16 or 32)");
+
+    match width.as_ref() {
+        "8"  => 8,
+        "16" => 16,
+        "32" => 32,
+        _    => fail(
+            "usage",
+            &format!("Invalid width: {} (expected 8, 16 or 32)", width),
+        ),
+    }
+}
+
+/// Makes sure the address is aligned to the access width. Unaligned accesses
+/// aren't supported by every part of the memory, and peripheral registers
+/// never are.
+fn check_alignment(address: u32, width: u32) {
+    if address % (width / 8) != 0 {
+        fail("usage", &format!(
+            "Address 0x{:0>8X} isn't aligned to {} bits",
+            address, width,
+        ));
+    }
+}
+
+/// Returns whether to transfer files without XMODEM, which depends on the USB
+/// port the device is connected through. For devices the uploader doesn't
+/// recognize, like the simulator, pass `--raw-transfers` to force it.
+fn raw_transfers(device_path: &str) -> bool {
+    if env::args().any(|arg| arg == "--raw-transfers") {
+        return true;
+    }
+
+    match discovery::kind_of(device_path) {
+        Ok(Some(kind)) => kind.raw_transfers(),
+        _              => false,
+    }
+}
+
+/// Returns how often failed operations should be retried, if given with
+/// `--retries=<n>`. See `SamBa::set_retries`.
+fn retries() -> Option<u32> {
+    env::args()
+        .find(|arg| arg.starts_with("--retries="))
+        .map(|arg|
+            parse_u32(&arg["--retries=".len() ..]).unwrap_or_else(|_|
+                fail("usage", "Failed to parse number of retries")
+            )
+        )
+}
+
+fn event(name: &str) -> json::Object {
+    json::Object::new().string("event", name)
+}
+
+fn emit(event: json::Object) {
+    print!("{}\n", event);
+}
+
+fn emit_chip(sam_ba: &mut SamBa, flash: &Flash) {
+    let chip_id = exit_on_error(
+        ChipId::read(sam_ba),
+        "Failed to read chip ID",
+    );
+
+    emit(event("chip")
+        .number("cidr", chip_id.cidr as u64)
+        .string("architecture", chip_id.architecture_name())
+        .number("flash_size", flash.size() as u64)
+        .number("page_size", flash.page_size() as u64)
+        .number("sram_size", chip_id.sram_size() as u64)
+    );
 }
@@ -0,0 +1,551 @@
+// Support for the build metadata that programs can embed, so we can tell
+// which build is on a board. The blink program places it right after its
+// vector table (see blink/src/metadata.rs).
+//
+// The metadata block is 88 bytes long and starts on a word boundary within the
+// first `SEARCH_LENGTH` bytes of the image. All numbers are little-endian.
+//
+// | Offset | Size | Field                                              |
+// |--------|------|----------------------------------------------------|
+// |      0 |    8 | Magic number, "BLNKMETA"                           |
+// |      8 |   24 | Version, UTF-8, padded with zeros                  |
+// |     32 |   40 | Git commit hash, hexadecimal, padded with zeros    |
+// |     72 |    4 | Build time, seconds since the Unix epoch           |
+// |     76 |    4 | Image length in bytes, starting at the image start |
+// |     80 |    4 | CRC32 of the image (see below)                     |
+// |     84 |    4 | Reserved, zero                                     |
+//
+// The image starts at the segment that contains the block and includes the
+// segments that directly follow it, like initialized data that's placed after
+// the code. The CRC32 covers the first `image_length` bytes of the image, with
+// the CRC32 field itself counted as zeros. The firmware only contains the magic
+// number. Everything else is filled in after linking, by `stamp`.
+
+
+use std::fmt;
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::prelude::*;
+use std::path::{
+    Path,
+    PathBuf,
+};
+use std::time::{
+    SystemTime,
+    UNIX_EPOCH,
+};
+
+use byteorder::{
+    ByteOrder,
+    LittleEndian,
+};
+
+use applet::Applet;
+use applet::crc32;
+use elf;
+use image::{
+    Image,
+    Segment,
+};
+use result;
+use sam_ba::{
+    SamBa,
+    Transport,
+};
+
+
+pub const MAGIC: &[u8; 8] = b"BLNKMETA";
+
+/// The size of the metadata block in bytes.
+pub const SIZE: usize = 88;
+
+/// How far from the start of the image the metadata block is looked for.
+pub const SEARCH_LENGTH: usize = 1024;
+
+const VERSION     : (usize, usize) = (8, 24);
+const GIT_HASH    : (usize, usize) = (32, 40);
+const TIMESTAMP   : usize          = 72;
+const IMAGE_LENGTH: usize          = 76;
+const CRC32       : usize          = 80;
+
+
+/// The metadata of an image, either from a file or from the flash memory.
+pub struct Metadata {
+    /// The address of the start of the image.
+    pub image_address: u32,
+
+    /// The offset of the metadata block from the start of the image.
+    pub offset: usize,
+
+    pub version     : String,
+    pub git_hash    : String,
+    pub timestamp   : u32,
+    pub image_length: u32,
+    pub crc32       : u32,
+}
+
+impl Metadata {
+    /// Looks for the metadata block in the image contents, which start at the
+    /// given address.
+    pub fn find(data: &[u8], image_address: u32) -> Option<Metadata> {
+        find_block(data).map(|offset| {
+            let block = &data[offset .. offset + SIZE];
+
+            Metadata {
+                image_address: image_address,
+                offset       : offset,
+                version      : read_text(block, VERSION),
+                git_hash     : read_text(block, GIT_HASH),
+                timestamp    : LittleEndian::read_u32(&block[TIMESTAMP ..]),
+                image_length : LittleEndian::read_u32(&block[IMAGE_LENGTH ..]),
+                crc32        : LittleEndian::read_u32(&block[CRC32 ..]),
+            }
+        })
+    }
+
+    /// Looks for the metadata block in the image that starts at the given
+    /// address on the device.
+    pub fn read<T>(sam_ba: &mut SamBa<T>, image_address: u32)
+        -> result::Result<Option<Metadata>>
+        where T: Transport
+    {
+        let mut data = vec![0; SEARCH_LENGTH];
+        try!(sam_ba.receive_file(image_address, &mut data));
+
+        Ok(Metadata::find(&data, image_address))
+    }
+
+    /// Returns whether the block has been filled in. If it hasn't, the
+    /// program was linked, but not stamped.
+    pub fn is_stamped(&self) -> bool {
+        self.image_length != 0
+    }
+
+    /// Computes the CRC32 of the image contents, the same way `stamp` did.
+    /// Returns `None`, if there's less data than the image should contain.
+    pub fn checksum(&self, data: &[u8]) -> Option<u32> {
+        let length = self.image_length as usize;
+        if data.len() < length || length < self.offset + SIZE {
+            return None;
+        }
+
+        let mut data = data[.. length].to_vec();
+        LittleEndian::write_u32(&mut data[self.offset + CRC32 ..], 0);
+
+        Some(crc32(&data))
+    }
+
+    /// Reads the image from the device and computes its CRC32. The caller
+    /// needs to make sure `image_length` is plausible, as that many bytes are
+    /// read.
+    ///
+    /// If the applet is passed, it computes the CRC32 on the device instead,
+    /// so only the result needs to be transferred. See `checksum_from_crc32`.
+    pub fn read_checksum<T>(&self,
+        sam_ba: &mut SamBa<T>,
+        applet: Option<&Applet>,
+    )
+        -> result::Result<Option<u32>>
+        where T: Transport
+    {
+        if let Some(applet) = applet {
+            let crc = try!(
+                applet.crc32(sam_ba, self.image_address, self.image_length)
+            );
+            return Ok(self.checksum_from_crc32(crc));
+        }
+
+        let mut data = vec![0; self.image_length as usize];
+        try!(sam_ba.receive_file(self.image_address, &mut data));
+
+        Ok(self.checksum(&data))
+    }
+
+    /// Computes what `checksum` would return, from the CRC32 of the image as
+    /// it is, with the CRC32 field filled in.
+    ///
+    /// CRC32 is affine: For data `a` and `b` of the same length `n`,
+    /// `crc32(a ^ b) == crc32(a) ^ crc32(b) ^ crc32(zeros(n))`. The image with
+    /// the field counted as zeros is the image as it is, XOR data that is zero
+    /// except for the field's value, so its CRC32 can be computed on the host
+    /// without the image.
+    pub fn checksum_from_crc32(&self, crc: u32) -> Option<u32> {
+        let length = self.image_length as usize;
+        if length < self.offset + SIZE {
+            return None;
+        }
+
+        let mut field = vec![0; length];
+        LittleEndian::write_u32(&mut field[self.offset + CRC32 ..], self.crc32);
+        let zeros = crc32(&vec![0; length]);
+
+        Some(crc ^ crc32(&field) ^ zeros)
+    }
+}
+
+
+/// Fills in the metadata block of an ELF file or raw binary. The build time
+/// is the current time, the image length is the length of what `contents`
+/// returns.
+///
+/// The file is replaced as a whole, so it's never left half written.
+pub fn stamp<P: AsRef<Path>>(path: P, version: &str, git_hash: &str)
+    -> result::Result<Metadata>
+{
+    let path = path.as_ref();
+
+    let image               = try!(Image::load(path));
+    let (address, mut data) = try!(contents(&image).ok_or(Error::NotFound));
+
+    let offset = try!(find_block(&data).ok_or(Error::NotFound));
+
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs() as u32)
+        .unwrap_or(0);
+    let length = data.len() as u32;
+
+    {
+        let block = &mut data[offset .. offset + SIZE];
+
+        try!(write_text(block, VERSION, version, "version"));
+        try!(write_text(block, GIT_HASH, git_hash, "git hash"));
+        LittleEndian::write_u32(&mut block[TIMESTAMP ..], timestamp);
+        LittleEndian::write_u32(&mut block[IMAGE_LENGTH ..], length);
+        LittleEndian::write_u32(&mut block[CRC32 ..], 0);
+    }
+
+    let checksum = crc32(&data);
+    LittleEndian::write_u32(&mut data[offset + CRC32 ..], checksum);
+
+    // Write the block back to where it came from in the file.
+    let mut file_data = Vec::new();
+    try!(try!(File::open(path)).read_to_end(&mut file_data));
+
+    let block_address = address + offset as u32;
+    let file_offset = if elf::is_elf(&file_data) {
+        try!(
+            try!(elf::file_offset(&file_data, block_address, SIZE as u32))
+                .ok_or(Error::UnsupportedFormat)
+        )
+    }
+    else if image.segments().len() == 1 && file_data.len() == data.len() {
+        offset
+    }
+    else {
+        return Err(Error::UnsupportedFormat.into());
+    };
+
+    file_data[file_offset .. file_offset + SIZE]
+        .copy_from_slice(&data[offset .. offset + SIZE]);
+    try!(replace_file(path, &file_data));
+
+    Ok(Metadata::find(&data, address).expect("Metadata was just written"))
+}
+
+/// Returns the address the image starts at and its contents, if it has a
+/// metadata block. The contents are those of the segment that contains the
+/// block, followed by the segments that directly follow it in memory. Other
+/// segments aren't covered by the metadata, so they don't need to be loaded,
+/// no matter how far away they are.
+pub fn contents(image: &Image) -> Option<(u32, Vec<u8>)> {
+    let mut segments: Vec<&Segment> = image.segments().iter().collect();
+    segments.sort_by_key(|segment| segment.address);
+
+    segments
+        .iter()
+        .position(|segment| find_block(&segment.data).is_some())
+        .map(|first| {
+            let address  = segments[first].address;
+            let mut data = Vec::new();
+
+            for segment in &segments[first ..] {
+                let end = address as u64 + data.len() as u64;
+                if segment.address as u64 != end {
+                    break;
+                }
+                data.extend_from_slice(&segment.data);
+            }
+
+            (address, data)
+        })
+}
+
+/// Formats a build time as date and time in UTC, like
+/// "2016-03-01 12:34:56 UTC".
+pub fn format_timestamp(timestamp: u32) -> String {
+    let days    = timestamp / 86400;
+    let seconds = timestamp % 86400;
+
+    // Converts days since the epoch into a date in the Gregorian calendar.
+    // See http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+    let days        = days + 719468;
+    let era         = days / 146097;
+    let day_of_era  = days - era * 146097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524
+            - day_of_era / 146096) / 365;
+    let day_of_year =
+        day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day         = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month       = if month_index < 10 { month_index + 3 }
+                      else { month_index - 9 };
+    let year        = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    format!(
+        "{}-{:0>2}-{:0>2} {:0>2}:{:0>2}:{:0>2} UTC",
+        year, month, day,
+        seconds / 3600, seconds / 60 % 60, seconds % 60,
+    )
+}
+
+
+#[derive(Debug)]
+pub enum Error {
+    /// The image contains no metadata block.
+    NotFound,
+
+    /// The text doesn't fit into the field with the given name.
+    TooLong(&'static str),
+
+    /// The block can only be filled in in ELF files and raw binaries.
+    UnsupportedFormat,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::NotFound =>
+                write!(f, "No metadata block found"),
+            Error::TooLong(field) =>
+                write!(f, "The {} is too long for the metadata block", field),
+            Error::UnsupportedFormat =>
+                write!(f, "Only ELF files and raw binaries can be stamped"),
+        }
+    }
+}
+
+
+/// Returns the offset of the metadata block, if there is one.
+fn find_block(data: &[u8]) -> Option<usize> {
+    let end = if data.len() < SEARCH_LENGTH { data.len() }
+              else { SEARCH_LENGTH };
+
+    (0 .. end)
+        .filter(|offset| offset % 4 == 0)
+        .find(|&offset|
+            offset + SIZE <= data.len() && data[offset ..].starts_with(MAGIC)
+        )
+}
+
+/// Writes the data to a temporary file next to the file, which then replaces
+/// it. The file keeps its permissions, so an executable stays executable.
+fn replace_file(path: &Path, data: &[u8]) -> io::Result<()> {
+    let mut temporary = path.as_os_str().to_owned();
+    temporary.push(".tmp");
+    let temporary = PathBuf::from(temporary);
+
+    let result = File::create(&temporary)
+        .and_then(|mut file| {
+            try!(file.write_all(data));
+            file.sync_all()
+        })
+        .and_then(|_| fs::metadata(path))
+        .and_then(|metadata|
+            fs::set_permissions(&temporary, metadata.permissions())
+        )
+        .and_then(|_| fs::rename(&temporary, path));
+
+    if result.is_err() {
+        let _ = fs::remove_file(&temporary);
+    }
+
+    result
+}
+
+fn read_text(block: &[u8], (offset, length): (usize, usize)) -> String {
+    let field = &block[offset .. offset + length];
+    let end   = field.iter().position(|&byte| byte == 0).unwrap_or(length);
+
+    String::from_utf8_lossy(&field[.. end]).into_owned()
+}
+
+fn write_text(
+    block            : &mut [u8],
+    (offset, length) : (usize, usize),
+    text             : &str,
+    name             : &'static str,
+)
+    -> Result<(), Error>
+{
+    if text.len() > length {
+        return Err(Error::TooLong(name));
+    }
+
+    let field = &mut block[offset .. offset + length];
+    for byte in field.iter_mut() {
+        *byte = 0;
+    }
+    field[.. text.len()].copy_from_slice(text.as_bytes());
+
+    Ok(())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+    use std::fs::File;
+    use std::io::prelude::*;
+    use std::process;
+
+    use byteorder::{
+        ByteOrder,
+        LittleEndian,
+    };
+
+    use applet::crc32;
+    use elf;
+    use elf::tests::Segment;
+    use image::Image;
+
+    use super::{
+        contents,
+        format_timestamp,
+        stamp,
+        Metadata,
+        CRC32,
+        MAGIC,
+        SIZE,
+    };
+
+
+    /// Returns a program with an empty metadata block after a vector table.
+    fn program(length: usize) -> Vec<u8> {
+        let mut data: Vec<u8> = (0 .. length).map(|i| i as u8).collect();
+        data[0x40 .. 0x40 + SIZE].copy_from_slice(&[0; SIZE]);
+        data[0x40 .. 0x48].copy_from_slice(MAGIC);
+        data
+    }
+
+
+    #[test]
+    fn finds_the_block_on_word_boundaries_only() {
+        let mut data = vec![0; 0x200];
+        data[0x42 .. 0x4a].copy_from_slice(MAGIC);
+        assert!(Metadata::find(&data, 0x80000).is_none());
+
+        data[0x44 .. 0x4c].copy_from_slice(MAGIC);
+        data[0x44 + 8 .. 0x44 + 11].copy_from_slice(b"1.0");
+        let metadata = Metadata::find(&data, 0x80000).unwrap();
+        assert_eq!(metadata.offset, 0x44);
+        assert_eq!(metadata.version, "1.0");
+        assert!(!metadata.is_stamped());
+    }
+
+    #[test]
+    fn ignores_blocks_beyond_the_search_length_or_the_data() {
+        let mut data = vec![0; 0x800];
+        data[0x400 .. 0x408].copy_from_slice(MAGIC);
+        assert!(Metadata::find(&data, 0x80000).is_none());
+
+        // The block would extend beyond the end of the data.
+        let mut data = vec![0; 0x80];
+        data[0x40 .. 0x48].copy_from_slice(MAGIC);
+        assert!(Metadata::find(&data, 0x80000).is_none());
+    }
+
+    #[test]
+    fn formats_timestamps_as_utc() {
+        assert_eq!(format_timestamp(0), "1970-01-01 00:00:00 UTC");
+        assert_eq!(format_timestamp(951782400), "2000-02-29 00:00:00 UTC");
+        assert_eq!(format_timestamp(1456835696), "2016-03-01 12:34:56 UTC");
+        assert_eq!(format_timestamp(0xFFFFFFFF), "2106-02-07 06:28:15 UTC");
+    }
+
+    #[test]
+    fn contents_only_cover_the_segments_that_follow_the_block() {
+        let code = program(0x100);
+        let image = Image::new(vec![
+            // Far below the image, so including it would take 512 MiB.
+            ::image::Segment {
+                address: 0x00000000,
+                data   : vec![0; 4],
+            },
+            ::image::Segment {
+                address: 0x20080000,
+                data   : vec![1; 4],
+            },
+            ::image::Segment {
+                address: 0x00080000,
+                data   : code.clone(),
+            },
+            ::image::Segment {
+                address: 0x00080100,
+                data   : vec![2; 8],
+            },
+        ]).unwrap();
+
+        let (address, data) = contents(&image).unwrap();
+        assert_eq!(address, 0x80000);
+        assert_eq!(&data[.. 0x100], &code[..]);
+        assert_eq!(&data[0x100 ..], &[2; 8]);
+    }
+
+    #[test]
+    fn stamps_elf_files() {
+        let code = program(0x200);
+        let data = [5, 6, 7, 8];
+        let file = elf::tests::build(&[
+            Segment {
+                physical_address: 0x80000,
+                virtual_address : 0x80000,
+                data            : &code,
+                memory_size     : code.len() as u32,
+            },
+            Segment {
+                physical_address: 0x80200,
+                virtual_address : 0x20070000,
+                data            : &data,
+                memory_size     : data.len() as u32,
+            },
+        ]);
+
+        let path = env::temp_dir()
+            .join(format!("upload-stamp-test-{}.elf", process::id()));
+        File::create(&path).unwrap().write_all(&file).unwrap();
+
+        let result = stamp(&path, "1.2.3", "0123abcd");
+        assert!(!path.with_extension("elf.tmp").exists());
+
+        let mut stamped = Vec::new();
+        File::open(&path).unwrap().read_to_end(&mut stamped).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        let metadata = result.unwrap();
+        assert_eq!(metadata.image_address, 0x80000);
+        assert_eq!(metadata.offset, 0x40);
+        assert_eq!(metadata.version, "1.2.3");
+        assert_eq!(metadata.git_hash, "0123abcd");
+        assert_eq!(metadata.image_length, 0x204);
+
+        // Only the block has changed, and the checksum covers both segments.
+        let block = 52 + 2 * 32 + 0x40 .. 52 + 2 * 32 + 0x40 + SIZE;
+        assert_eq!(&stamped[.. block.start], &file[.. block.start]);
+        assert_eq!(&stamped[block.end ..], &file[block.end ..]);
+
+        let (_, image) = contents(&elf::parse(&stamped).unwrap()).unwrap();
+
+        let mut expected = code.clone();
+        expected.extend_from_slice(&data);
+        expected[0x40 .. 0x40 + SIZE].copy_from_slice(&stamped[block]);
+        LittleEndian::write_u32(&mut expected[0x40 + CRC32 ..], 0);
+        assert_eq!(metadata.crc32, crc32(&expected));
+        assert_eq!(metadata.checksum(&image), Some(metadata.crc32));
+        assert_eq!(metadata.checksum_from_crc32(crc32(&image)),
+            Some(metadata.crc32));
+    }
+}
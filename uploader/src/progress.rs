@@ -0,0 +1,70 @@
+// Reporting of the progress `Flash::write`, `Flash::write_changed` and
+// `Flash::verify` make, for programs that want to show it to a user.
+
+
+use std::time::Instant;
+
+use flash::Progress;
+use json;
+
+
+/// Reports the progress of writing or verifying pages, either as JSON events,
+/// or as a progress bar with the transfer rate. The progress bar goes to
+/// stderr, so it doesn't end up in logs of the regular output. JSON events go
+/// to stdout, one per line.
+pub struct Report {
+    label: &'static str,
+    json : bool,
+    start: Instant,
+    bytes: u64,
+}
+
+impl Report {
+    /// Creates a report that shows the label in front of the progress bar,
+    /// or emits JSON events instead, if `json` is set.
+    pub fn new(label: &'static str, json: bool) -> Self {
+        Report {
+            label: label,
+            json : json,
+            start: Instant::now(),
+            bytes: 0,
+        }
+    }
+
+    /// Reports a page. Pass this to `Flash::write` and friends.
+    pub fn report(&mut self, progress: Progress) {
+        self.bytes += progress.bytes as u64;
+
+        let elapsed = self.start.elapsed();
+        let seconds =
+            elapsed.as_secs() as f64 + elapsed.subsec_nanos() as f64 / 1e9;
+        let rate = self.bytes as f64 / seconds.max(0.001);
+
+        if self.json {
+            print!("{}\n", json::Object::new()
+                .string("event", "page")
+                .number("address", progress.address as u64)
+                .string("action", progress.action.name())
+                .number("page", progress.pages_done as u64)
+                .number("pages", progress.pages_total as u64)
+                .float("bytes_per_second", rate)
+            );
+            return;
+        }
+
+        let width  = 30;
+        let filled = width * progress.pages_done / progress.pages_total;
+
+        eprint!("\r{:<10}[{}{}] {}/{} pages, {:.1} KiB/s",
+            self.label,
+            "#".repeat(filled as usize),
+            "-".repeat((width - filled) as usize),
+            progress.pages_done, progress.pages_total,
+            rate / 1024.0,
+        );
+
+        if progress.pages_done == progress.pages_total {
+            eprint!("\n");
+        }
+    }
+}
@@ -0,0 +1,346 @@
+// Runs programs from SRAM, without touching the flash memory. The program
+// needs to be linked for SRAM, e.g. using `blink/linker-script-ram.ld`.
+
+
+use std::fmt;
+
+use image::Image;
+use result::Result;
+use sam_ba::{
+    SamBa,
+    Transport,
+};
+
+
+// The SRAM, as seen in its contiguous mapping. See data sheet, section 7.2.1.
+pub const SRAM_ADDR: u32 = 0x20070000;
+pub const SRAM_SIZE: u32 = 0x18000;
+
+// The first SRAM block is also mapped to its own address range.
+const SRAM0_ADDR: u32 = 0x20000000;
+const SRAM0_SIZE: u32 = 0x10000;
+
+// The SAM-BA monitor uses the beginning of the SRAM for itself. Programs must
+// not be loaded there, or the monitor will crash before it can start them.
+pub const RESERVED_SIZE: u32 = 0x1000;
+
+
+// Code that starts the program whose vector table address follows it.
+//
+// The go command of the SAM-BA monitor just branches to an address, which
+// would leave the program running on the monitor's stack and vector table,
+// with the monitor's interrupts enabled. Instead, we jump into this, which
+// sets the program up like a reset would.
+const TRAMPOLINE: [u16; 18] = [
+    0xb672, //     cpsid i
+    0x4908, //     ldr   r1, =NVIC_ICER0
+    0x2200, //     movs  r2, #0
+    0x43d2, //     mvns  r2, r2
+    0x600a, //     str   r2, [r1]          ; disable all interrupts
+    0x604a, //     str   r2, [r1, #4]
+    0x4906, //     ldr   r1, =SYST_CSR
+    0x2200, //     movs  r2, #0
+    0x600a, //     str   r2, [r1]          ; disable SysTick
+    0x4806, //     ldr   r0, =vector_table
+    0x4906, //     ldr   r1, =SCB_VTOR
+    0x6008, //     str   r0, [r1]
+    0x6801, //     ldr   r1, [r0]
+    0x468d, //     mov   sp, r1
+    0x6841, //     ldr   r1, [r0, #4]
+    0xb662, //     cpsie i
+    0x4708, //     bx    r1
+    0xbf00, //     nop
+];
+
+const NVIC_ICER0: u32 = 0xe000e180;
+const SYST_CSR  : u32 = 0xe000e010;
+const SCB_VTOR  : u32 = 0xe000ed08;
+
+
+/// Loads the image into SRAM and starts it. The image's vector table is
+/// expected at its lowest address.
+pub fn run<T: Transport>(sam_ba: &mut SamBa<T>, image: &Image) -> Result<()> {
+    let vector_table = try!(check(image));
+
+    for segment in image.segments() {
+        try!(sam_ba.send_file(segment.address, &segment.data));
+    }
+
+    // The trampoline goes right after the image. `check` made sure there's
+    // enough room.
+    let address = trampoline_address(image);
+    try!(sam_ba.send_file(address, &trampoline(vector_table)));
+
+    try!(sam_ba.go(address | 0x1));
+
+    Ok(())
+}
+
+/// Makes sure the image can run from SRAM, and returns the address of its
+/// vector table.
+pub fn check(image: &Image) -> Result<u32> {
+    let vector_table = image.segments()
+        .iter()
+        .map(|segment| segment.address)
+        .min();
+
+    let vector_table = match vector_table {
+        Some(vector_table) => vector_table,
+        None               => return Err(Error::Empty.into()),
+    };
+
+    for segment in image.segments() {
+        let start = segment.address;
+        let end   = start.checked_add(segment.data.len() as u32);
+
+        let outside = match end {
+            Some(end) =>
+                start < SRAM_ADDR + RESERVED_SIZE
+                    || end > SRAM_ADDR + SRAM_SIZE,
+            None =>
+                true,
+        };
+        if outside {
+            return Err(Error::OutsideSram(start).into());
+        }
+    }
+
+    // Bits 0 to 6 of the vector table offset are reserved, and the table
+    // needs to be aligned to its size anyway. See the ARMv7-M Architecture
+    // Reference Manual, section B3.2.5.
+    if vector_table & 0xff != 0 {
+        return Err(Error::VectorTableAlignment(vector_table).into());
+    }
+
+    let (stack_pointer, reset_handler) = match (
+        image.read_word(vector_table),
+        image.read_word(vector_table + 4),
+    ) {
+        (Some(stack_pointer), Some(reset_handler)) =>
+            (stack_pointer, reset_handler),
+        _ =>
+            return Err(Error::Empty.into()),
+    };
+
+    if !is_stack_pointer(stack_pointer) {
+        return Err(Error::StackPointer(stack_pointer).into());
+    }
+    if reset_handler & 0x1 == 0
+        || image.read_word(reset_handler & !0x1).is_none()
+    {
+        return Err(Error::ResetHandler(reset_handler).into());
+    }
+
+    let trampoline_end =
+        trampoline_address(image) + trampoline(vector_table).len() as u32;
+    if trampoline_end > SRAM_ADDR + SRAM_SIZE {
+        return Err(Error::NoRoom.into());
+    }
+
+    Ok(vector_table)
+}
+
+
+#[derive(Debug)]
+pub enum Error {
+    /// The image doesn't contain a vector table.
+    Empty,
+
+    /// The segment at the given address isn't within the usable SRAM.
+    OutsideSram(u32),
+
+    /// The vector table at the given address isn't aligned to 256 bytes.
+    VectorTableAlignment(u32),
+
+    /// The initial stack pointer doesn't point into SRAM.
+    StackPointer(u32),
+
+    /// The reset handler isn't Thumb code within the image.
+    ResetHandler(u32),
+
+    /// There's no room after the image to put the trampoline.
+    NoRoom,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::Empty =>
+                write!(f, "Image doesn't contain a vector table"),
+            Error::OutsideSram(address) =>
+                write!(f,
+                    "Segment at 0x{:0>8X} is outside of usable SRAM \
+                    (0x{:0>8X}-0x{:0>8X}). Is the image linked for SRAM?",
+                    address,
+                    SRAM_ADDR + RESERVED_SIZE, SRAM_ADDR + SRAM_SIZE - 1,
+                ),
+            Error::VectorTableAlignment(address) =>
+                write!(f,
+                    "Vector table at 0x{:0>8X} isn't aligned to 256 bytes",
+                    address,
+                ),
+            Error::StackPointer(address) =>
+                write!(f,
+                    "Initial stack pointer 0x{:0>8X} doesn't point into SRAM",
+                    address,
+                ),
+            Error::ResetHandler(address) =>
+                write!(f,
+                    "Reset handler 0x{:0>8X} isn't Thumb code within the image",
+                    address,
+                ),
+            Error::NoRoom =>
+                write!(f, "No room in SRAM after the image"),
+        }
+    }
+}
+
+
+/// Returns whether the value is a valid initial stack pointer. The stack grows
+/// downwards, so the stack pointer may point right after the end of the SRAM.
+pub fn is_stack_pointer(value: u32) -> bool {
+    let within = |start: u32, size: u32| value > start && value <= start + size;
+
+    within(SRAM_ADDR, SRAM_SIZE) || within(SRAM0_ADDR, SRAM0_SIZE)
+}
+
+fn trampoline_address(image: &Image) -> u32 {
+    let end = image.segments()
+        .iter()
+        .map(|segment| segment.address + segment.data.len() as u32)
+        .max()
+        .unwrap_or(SRAM_ADDR + RESERVED_SIZE);
+
+    (end + 3) & !0x3
+}
+
+/// Returns the trampoline's machine code, followed by the constants it loads.
+fn trampoline(vector_table: u32) -> Vec<u8> {
+    let mut code = Vec::new();
+
+    for &instruction in TRAMPOLINE.iter() {
+        code.push(instruction as u8);
+        code.push((instruction >> 8) as u8);
+    }
+    for &word in [NVIC_ICER0, SYST_CSR, vector_table, SCB_VTOR].iter() {
+        for i in 0 .. 4 {
+            code.push((word >> (i * 8)) as u8);
+        }
+    }
+
+    code
+}
+
+
+#[cfg(test)]
+mod tests {
+    use image::{
+        Image,
+        Segment,
+    };
+    use result;
+
+    use super::{
+        check,
+        Error,
+        SRAM_ADDR,
+        SRAM_SIZE,
+    };
+
+
+    /// Returns an image at the given address, starting with a vector table.
+    fn program(address: u32, length: usize, stack_pointer: u32, reset: u32)
+        -> Image
+    {
+        let mut data = vec![0; length];
+        for i in 0 .. 4 {
+            data[i]     = (stack_pointer >> (i * 8)) as u8;
+            data[4 + i] = (reset >> (i * 8)) as u8;
+        }
+
+        Image::new(vec![
+            Segment {
+                address: address,
+                data   : data,
+            },
+        ])
+        .unwrap()
+    }
+
+    fn check_error(image: &Image) -> Error {
+        match check(image) {
+            Err(result::Error::Ram(error)) => error,
+            result => panic!("Unexpected result: {:?}", result),
+        }
+    }
+
+
+    #[test]
+    fn accepts_images_linked_for_sram() {
+        let image = program(0x20071000, 0x200, 0x20088000, 0x20071101);
+        assert_eq!(check(&image).unwrap(), 0x20071000);
+
+        // The stack may also be in the separate mapping of the first block.
+        let image = program(0x20071000, 0x200, 0x20008000, 0x20071101);
+        assert_eq!(check(&image).unwrap(), 0x20071000);
+    }
+
+    #[test]
+    fn rejects_images_outside_the_usable_sram() {
+        match check_error(&Image::empty()) {
+            Error::Empty => (),
+            error => panic!("Unexpected error: {:?}", error),
+        }
+
+        // Linked for flash
+        let image = program(0x00080000, 0x200, 0x20088000, 0x00080101);
+        match check_error(&image) {
+            Error::OutsideSram(0x00080000) => (),
+            error => panic!("Unexpected error: {:?}", error),
+        }
+
+        // Overlaps the memory the SAM-BA monitor uses
+        let image = program(0x20070F00, 0x200, 0x20088000, 0x20071001);
+        match check_error(&image) {
+            Error::OutsideSram(0x20070F00) => (),
+            error => panic!("Unexpected error: {:?}", error),
+        }
+
+        // No room for the trampoline
+        let address = SRAM_ADDR + SRAM_SIZE - 0x100;
+        let image   = program(address, 0x100, 0x20088000, address + 0x41);
+        match check_error(&image) {
+            Error::NoRoom => (),
+            error => panic!("Unexpected error: {:?}", error),
+        }
+    }
+
+    #[test]
+    fn rejects_invalid_vector_tables() {
+        let image = program(0x20071080, 0x200, 0x20088000, 0x20071101);
+        match check_error(&image) {
+            Error::VectorTableAlignment(0x20071080) => (),
+            error => panic!("Unexpected error: {:?}", error),
+        }
+
+        let image = program(0x20071000, 0x200, 0x20090000, 0x20071101);
+        match check_error(&image) {
+            Error::StackPointer(0x20090000) => (),
+            error => panic!("Unexpected error: {:?}", error),
+        }
+
+        // Not Thumb code
+        let image = program(0x20071000, 0x200, 0x20088000, 0x20071100);
+        match check_error(&image) {
+            Error::ResetHandler(0x20071100) => (),
+            error => panic!("Unexpected error: {:?}", error),
+        }
+
+        // Outside the image
+        let image = program(0x20071000, 0x200, 0x20088000, 0x20072001);
+        match check_error(&image) {
+            Error::ResetHandler(0x20072001) => (),
+            error => panic!("Unexpected error: {:?}", error),
+        }
+    }
+}
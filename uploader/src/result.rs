@@ -1,16 +1,155 @@
+use std::fmt;
 use std::io;
 use std::result;
 
 use byteorder;
 
+use applet;
+use eefc;
+use elf;
+use flash;
+use ihex;
+use image;
+use metadata;
+use ram;
+use sam_ba;
+use srec;
+use svd;
+use xmodem;
+
 
 pub type Result<T> = result::Result<T, Error>;
 
 
 #[derive(Debug)]
 pub enum Error {
+    Applet(applet::Error),
     ByteOrder(byteorder::Error),
+    Eefc(eefc::Error),
+    Elf(elf::Error),
+    Flash(flash::CheckError),
+    Ihex(ihex::Error),
+    Image(image::Error),
     Io(io::Error),
+    Metadata(metadata::Error),
+    Ram(ram::Error),
+    SamBa(sam_ba::Error),
+    Srec(srec::Error),
+    Svd(svd::Error),
+    Xmodem(xmodem::Error),
+
+    /// An operation on the flash page at the given address failed.
+    Page(u32, Box<Error>),
+
+    /// The lock region at the given address is locked.
+    Locked(u32),
+}
+
+impl Error {
+    /// Returns a short identifier for the kind of error, for machine-readable
+    /// output. Unlike the messages, these are not going to change.
+    pub fn code(&self) -> &'static str {
+        match *self {
+            Error::Applet(_) =>
+                "applet",
+            Error::ByteOrder(_) =>
+                "io",
+            Error::Eefc(ref error) =>
+                match *error {
+                    eefc::Error::Command             => "flash_command",
+                    eefc::Error::Lock                => "flash_locked",
+                    eefc::Error::Timeout             => "flash_timeout",
+                    eefc::Error::UnexpectedStatus(_) => "flash_status",
+                },
+            Error::Elf(_) =>
+                "invalid_elf",
+            Error::Flash(_) =>
+                "invalid_image",
+            Error::Ihex(_) =>
+                "invalid_ihex",
+            Error::Image(_) =>
+                "invalid_image",
+            Error::Io(_) =>
+                "io",
+            Error::Metadata(_) =>
+                "metadata",
+            Error::Ram(_) =>
+                "invalid_ram_image",
+            Error::SamBa(ref error) =>
+                match *error {
+                    sam_ba::Error::NotResponding    => "not_responding",
+                    sam_ba::Error::OutOfRange(_, _) => "out_of_range",
+                },
+            Error::Srec(_) =>
+                "invalid_srec",
+            Error::Svd(_) =>
+                "invalid_svd",
+            Error::Xmodem(_) =>
+                "transfer",
+            Error::Page(_, ref error) =>
+                error.code(),
+            Error::Locked(_) =>
+                "locked",
+        }
+    }
+
+    /// Returns whether the error was caused by the communication with the
+    /// monitor, which means that repeating the operation might help.
+    pub fn is_communication(&self) -> bool {
+        match *self {
+            Error::ByteOrder(_) | Error::Io(_) | Error::Xmodem(_) =>
+                true,
+            Error::Page(_, ref error) =>
+                error.is_communication(),
+            _ =>
+                false,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::Applet(ref error) =>
+                write!(f, "{}", error),
+            Error::ByteOrder(ref error) =>
+                write!(f, "{}", error),
+            Error::Eefc(ref error) =>
+                write!(f, "{}", error),
+            Error::Elf(ref error) =>
+                write!(f, "Invalid ELF file: {:?}", error),
+            Error::Flash(ref error) =>
+                write!(f, "{}", error),
+            Error::Ihex(ref error) =>
+                write!(f, "Invalid Intel HEX file: {:?}", error),
+            Error::Image(ref error) =>
+                write!(f, "{}", error),
+            Error::Io(ref error) =>
+                write!(f, "{}", error),
+            Error::Metadata(ref error) =>
+                write!(f, "{}", error),
+            Error::Ram(ref error) =>
+                write!(f, "{}", error),
+            Error::SamBa(ref error) =>
+                write!(f, "{}", error),
+            Error::Srec(ref error) =>
+                write!(f, "Invalid S-record file: {:?}", error),
+            Error::Svd(ref error) =>
+                write!(f, "Invalid SVD file: {}", error),
+            Error::Xmodem(ref error) =>
+                write!(f, "Transfer failed: {:?}", error),
+            Error::Page(address, ref error) =>
+                write!(f, "Page at 0x{:0>8X}: {}", address, error),
+            Error::Locked(address) =>
+                write!(f, "Lock region at 0x{:0>8X} is locked", address),
+        }
+    }
+}
+
+impl From<applet::Error> for Error {
+    fn from(error: applet::Error) -> Self {
+        Error::Applet(error)
+    }
 }
 
 impl From<byteorder::Error> for Error {
@@ -19,8 +158,74 @@ impl From<byteorder::Error> for Error {
     }
 }
 
+impl From<eefc::Error> for Error {
+    fn from(error: eefc::Error) -> Self {
+        Error::Eefc(error)
+    }
+}
+
+impl From<elf::Error> for Error {
+    fn from(error: elf::Error) -> Self {
+        Error::Elf(error)
+    }
+}
+
+impl From<flash::CheckError> for Error {
+    fn from(error: flash::CheckError) -> Self {
+        Error::Flash(error)
+    }
+}
+
+impl From<ihex::Error> for Error {
+    fn from(error: ihex::Error) -> Self {
+        Error::Ihex(error)
+    }
+}
+
+impl From<image::Error> for Error {
+    fn from(error: image::Error) -> Self {
+        Error::Image(error)
+    }
+}
+
 impl From<io::Error> for Error {
     fn from(io_error: io::Error) -> Self {
         Error::Io(io_error)
     }
 }
+
+impl From<metadata::Error> for Error {
+    fn from(error: metadata::Error) -> Self {
+        Error::Metadata(error)
+    }
+}
+
+impl From<ram::Error> for Error {
+    fn from(error: ram::Error) -> Self {
+        Error::Ram(error)
+    }
+}
+
+impl From<sam_ba::Error> for Error {
+    fn from(error: sam_ba::Error) -> Self {
+        Error::SamBa(error)
+    }
+}
+
+impl From<srec::Error> for Error {
+    fn from(error: srec::Error) -> Self {
+        Error::Srec(error)
+    }
+}
+
+impl From<svd::Error> for Error {
+    fn from(error: svd::Error) -> Self {
+        Error::Svd(error)
+    }
+}
+
+impl From<xmodem::Error> for Error {
+    fn from(error: xmodem::Error) -> Self {
+        Error::Xmodem(error)
+    }
+}
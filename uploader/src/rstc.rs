@@ -0,0 +1,32 @@
+use result::{
+    Error,
+    Result,
+};
+use sam_ba::{
+    SamBa,
+    Transport,
+};
+
+
+// Control register of the Reset Controller. See data sheet, chapter 13.
+const RSTC_CR: u32 = 0x400e1a00;
+
+// Writes to the control register are ignored, unless they contain this key.
+const KEY: u32 = 0xa5 << 24;
+
+const PROCRST: u32 = 0x1;
+const PERRST : u32 = 0x4;
+
+
+/// Resets the processor and the peripherals, which starts the program in flash
+/// memory, if the boot mode GPNVM bit is set.
+///
+/// The device drops off the USB bus while it resets, if it is connected via
+/// the native port. Since the command has been sent at that point, I/O errors
+/// are ignored.
+pub fn reset<T: Transport>(sam_ba: &mut SamBa<T>) -> Result<()> {
+    match sam_ba.write_word(RSTC_CR, KEY | PROCRST | PERRST) {
+        Err(Error::Io(_)) => Ok(()),
+        result            => result,
+    }
+}
@@ -1,43 +1,138 @@
+use std::cmp;
+use std::fmt;
+use std::io;
 use std::io::{
     copy,
     sink,
 };
 use std::io::prelude::*;
+use std::time::{
+    Duration,
+    Instant,
+};
 
 use byteorder::{
     LittleEndian,
     ReadBytesExt,
 };
-use serial;
 
+use chip_id;
 use result::Result;
 use utils::ignore_timeout;
+use xmodem;
+
+
+/// How often failed operations are retried, unless configured otherwise. See
+/// `SamBa::set_retries`.
+pub const DEFAULT_RETRIES: u32 = 3;
+
+
+/// How much memory `SamBa::dump` reads at once.
+const DUMP_CHUNK_SIZE: u32 = 4096;
+
+
+/// A connection to the SAM-BA monitor, usually a serial port.
+///
+/// Reads must time out with `io::ErrorKind::TimedOut`, instead of blocking
+/// forever, if no data arrives, like `serial::SystemPort` does. Some replies of
+/// the monitor have no defined length, and the only way to know they're
+/// complete is to wait for the timeout.
+pub trait Transport: Read + Write {}
+
+impl<T: Read + Write> Transport for T {}
 
 
 /// Interface to the SAM-BA monitor. See data sheet, chapter 20.4.
-pub struct SamBa {
-    port: serial::SystemPort,
+///
+/// The monitor's replies carry no framing, so a single stray or lost byte
+/// would shift every following reply. Operations that fail are therefore
+/// retried, after resynchronizing with the monitor. See `synchronize`.
+pub struct SamBa<T> {
+    port         : T,
+    retries      : u32,
+    raw_transfers: bool,
 }
 
-impl SamBa {
-    pub fn new(port: serial::SystemPort) -> Self {
+impl<T: Transport> SamBa<T> {
+    pub fn new(port: T) -> Self {
         SamBa {
-            port: port,
+            port         : port,
+            retries      : DEFAULT_RETRIES,
+            raw_transfers: false,
+        }
+    }
+
+    /// Sets how often a failed operation is retried before giving up.
+    pub fn set_retries(&mut self, retries: u32) {
+        self.retries = retries;
+    }
+
+    /// Makes `send_file` and `receive_file` transfer the data as is, instead
+    /// of using XMODEM. The monitor expects that on its USB port, but uses
+    /// XMODEM on the UART, which the Due's programming port is connected to.
+    /// See `discovery::Kind::raw_transfers`.
+    pub fn set_raw_transfers(&mut self, raw_transfers: bool) {
+        self.raw_transfers = raw_transfers;
+    }
+
+    /// Runs an operation, and if it fails because of the communication with
+    /// the monitor, resynchronizes and runs it again, up to the configured
+    /// number of retries. The single operations of this struct are retried
+    /// already. This is for operations that consist of several commands and
+    /// need to be repeated as a whole, like writing a flash page.
+    ///
+    /// Only operations that can be repeated without harm may be passed here.
+    pub fn retry<F, R>(&mut self, mut operation: F) -> Result<R>
+        where F: FnMut(&mut Self) -> Result<R>
+    {
+        let mut attempts = 0;
+
+        loop {
+            let error = match operation(self) {
+                Ok(value)  => return Ok(value),
+                Err(error) => error,
+            };
+
+            if attempts == self.retries || !error.is_communication() {
+                return Err(error);
+            }
+            attempts += 1;
+
+            try!(self.synchronize());
         }
     }
 
     /// Configures serial communication to be in normal mode (i.e. data is
-    /// binary).
+    /// binary), and makes sure the monitor's replies can be relied on.
     pub fn set_normal_mode(&mut self) -> Result<()> {
-        try!(write!(self.port, "N#"));
+        self.synchronize()
+    }
 
-        // SAM-BA seems to send some kind of reply to that command, but I can't
-        // find any information about what that reply actually is and why we
-        // would need it. Let's just throw away anything that comes back and
-        // never think about it again.
-        try!(ignore_timeout(copy(&mut self.port, &mut sink())));
+    /// Brings the monitor into a known state, discarding anything that might
+    /// be left over from an earlier session or a failed operation, like the
+    /// output of a terminal session or the rest of a reply.
+    ///
+    /// Returns `Error::NotResponding`, if the monitor doesn't reply as
+    /// expected, even after several attempts.
+    pub fn synchronize(&mut self) -> Result<()> {
+        for _ in 0 .. self.retries + 1 {
+            // Cancel any XMODEM transfer and terminate any partial command.
+            // The monitor ignores what it doesn't understand.
+            try!(self.port.write_all(&[xmodem::CAN, xmodem::CAN, b'#']));
+            try!(self.discard_input());
 
-        Ok(())
+            // SAM-BA seems to send some kind of reply to that command, but I
+            // can't find any information about what that reply actually is and
+            // why we would need it. Let's just throw it away.
+            try!(write!(self.port, "N#"));
+            try!(self.discard_input());
+
+            if self.probe() {
+                return Ok(());
+            }
+        }
+
+        Err(Error::NotResponding.into())
     }
 
     pub fn display_version(&mut self) -> Result<String> {
@@ -50,14 +145,275 @@ impl SamBa {
     }
 
     pub fn read_word(&mut self, address: u32) -> Result<u32> {
+        self.retry(|sam_ba| request_word(&mut sam_ba.port, address))
+    }
+
+    pub fn read_half_word(&mut self, address: u32) -> Result<u16> {
+        self.retry(|sam_ba| {
+            try!(write!(sam_ba.port, "h{:0>8X},#", address));
+            Ok(try!(sam_ba.port.read_u16::<LittleEndian>()))
+        })
+    }
+
+    pub fn read_byte(&mut self, address: u32) -> Result<u8> {
+        self.retry(|sam_ba| {
+            try!(write!(sam_ba.port, "o{:0>8X},#", address));
+            Ok(try!(sam_ba.port.read_u8()))
+        })
+    }
+
+    /// Reads a word from a register that changes when it's read, like the
+    /// status and result registers of the flash controller. Reading it again
+    /// would return a different value, so this isn't retried. If it fails, the
+    /// connection is still resynchronized, so the operation the read belongs
+    /// to can be repeated as a whole. See `retry`.
+    pub fn read_register(&mut self, address: u32) -> Result<u32> {
+        let result = request_word(&mut self.port, address);
+
+        if result.is_err() {
+            try!(self.synchronize());
+        }
+
+        result
+    }
+
+    /// Reads a word, like `read_word`, but keeps waiting for the reply until
+    /// the timeout has elapsed. This is needed after the go command, as the
+    /// monitor doesn't process any commands until the code it jumped to has
+    /// returned. Returns `Error::NotResponding` on timeout, and isn't retried,
+    /// as the code might not return at all. If only part of the reply
+    /// arrives, the read's error is returned right away.
+    pub fn wait_for_word(&mut self, address: u32, timeout: Duration)
+        -> Result<u32>
+    {
         try!(write!(self.port, "w{:0>8X},#", address));
-        let result = try!(self.port.read_u32::<LittleEndian>());
+
+        let start = Instant::now();
+
+        let mut buffer = [0; 4];
+        let mut n      = 0;
+        while n < buffer.len() {
+            match self.port.read(&mut buffer[n ..]) {
+                Ok(bytes_read) =>
+                    n += bytes_read,
+
+                // The monitor sends the reply all at once, so if it stops in
+                // the middle, a byte got lost.
+                Err(error)
+                    if error.kind() == io::ErrorKind::TimedOut && n > 0
+                =>
+                    return Err(error.into()),
+
+                Err(ref error) if error.kind() == io::ErrorKind::TimedOut =>
+                    if start.elapsed() >= timeout {
+                        return Err(Error::NotResponding.into());
+                    },
+
+                Err(error) =>
+                    return Err(error.into()),
+            }
+        }
+
+        let result = try!((&buffer[..]).read_u32::<LittleEndian>());
 
         Ok(result)
     }
 
+    /// Reads an arbitrary range of memory into the buffer. The memory is read
+    /// word by word, so neither the address nor the length need to be
+    /// aligned.
+    pub fn read_memory(&mut self, address: u32, buffer: &mut [u8])
+        -> Result<()>
+    {
+        let end = try!(end_of(address, buffer.len() as u32));
+
+        let mut word_address = address & !0x3;
+        while (word_address as u64) < end {
+            let word = try!(self.read_word(word_address));
+
+            for i in 0 .. 4 {
+                let byte_address = word_address + i;
+                if byte_address < address || byte_address as u64 >= end {
+                    continue;
+                }
+
+                let index = (byte_address - address) as usize;
+                buffer[index] = (word >> (i * 8)) as u8;
+            }
+
+            word_address = match word_address.checked_add(4) {
+                Some(word_address) => word_address,
+                None               => break,
+            };
+        }
+
+        Ok(())
+    }
+
     pub fn write_word(&mut self, address: u32, value: u32) -> Result<()> {
-        try!(write!(self.port, "W{:0>8X},{:0>8X}#", address, value));
+        self.retry(|sam_ba| {
+            try!(write!(sam_ba.port, "W{:0>8X},{:0>8X}#", address, value));
+            Ok(())
+        })
+    }
+
+    pub fn write_half_word(&mut self, address: u32, value: u16)
+        -> Result<()>
+    {
+        self.retry(|sam_ba| {
+            try!(write!(sam_ba.port, "H{:0>8X},{:0>4X}#", address, value));
+            Ok(())
+        })
+    }
+
+    pub fn write_byte(&mut self, address: u32, value: u8) -> Result<()> {
+        self.retry(|sam_ba| {
+            try!(write!(sam_ba.port, "O{:0>8X},{:0>2X}#", address, value));
+            Ok(())
+        })
+    }
+
+    /// Writes data to memory, starting at the given address. This is much
+    /// faster than writing word by word, as the data is transferred in blocks
+    /// using the XMODEM protocol, or as is (see `set_raw_transfers`). See data
+    /// sheet, section 20.4.3.
+    pub fn send_file(&mut self, address: u32, data: &[u8]) -> Result<()> {
+        self.retry(|sam_ba| {
+            try!(write!(sam_ba.port, "S{:0>8X},{:0>8X}#", address, data.len()));
+
+            if sam_ba.raw_transfers {
+                try!(sam_ba.port.write_all(data));
+                return Ok(());
+            }
+
+            xmodem::send(&mut sam_ba.port, data)
+        })
+    }
+
+    /// Jumps to the code at the given address. The address needs to have the
+    /// Thumb bit set, like any other branch target on the Cortex-M3. If the
+    /// code returns, the monitor continues running. See data sheet, section
+    /// 20.4.3.
+    ///
+    /// This is never retried, as there's no way to tell whether the jump
+    /// happened.
+    pub fn go(&mut self, address: u32) -> Result<()> {
+        try!(write!(self.port, "G{:0>8X}#", address));
         Ok(())
     }
+
+    /// Reads memory, starting at the given address, until the buffer is full.
+    /// Like `send_file`, this transfers the data in XMODEM blocks, or as is.
+    pub fn receive_file(&mut self, address: u32, buffer: &mut [u8])
+        -> Result<()>
+    {
+        self.retry(|sam_ba| {
+            try!(write!(sam_ba.port,
+                "R{:0>8X},{:0>8X}#", address, buffer.len(),
+            ));
+
+            if sam_ba.raw_transfers {
+                try!(sam_ba.port.read_exact(buffer));
+                return Ok(());
+            }
+
+            xmodem::receive(&mut sam_ba.port, buffer)
+        })
+    }
+
+    /// Reads a range of memory in chunks and passes each chunk, along with
+    /// its address, to the closure. This is meant for ranges that are too
+    /// large to read into memory at once. The chunks are read with
+    /// `receive_file`.
+    pub fn dump<F>(&mut self, address: u32, length: u32, mut f: F)
+        -> Result<()>
+        where F: FnMut(u32, &[u8]) -> Result<()>
+    {
+        try!(end_of(address, length));
+
+        let mut buffer = vec![0; DUMP_CHUNK_SIZE as usize];
+
+        let mut offset = 0;
+        while offset < length {
+            let chunk_size = cmp::min(DUMP_CHUNK_SIZE, length - offset);
+            let chunk      = &mut buffer[.. chunk_size as usize];
+
+            try!(self.receive_file(address + offset, chunk));
+            try!(f(address + offset, chunk));
+
+            offset += chunk_size;
+        }
+
+        Ok(())
+    }
+
+    /// Reads a register whose value is known not to change, twice, and makes
+    /// sure that nothing but the expected replies arrive.
+    fn probe(&mut self) -> bool {
+        let first  = request_word(&mut self.port, chip_id::CIDR);
+        let second = request_word(&mut self.port, chip_id::CIDR);
+
+        match (first, second, self.discard_input()) {
+            (Ok(first), Ok(second), Ok(0)) => first == second && first != 0,
+            _                              => false,
+        }
+    }
+
+    /// Throws away anything the monitor sends, until it stops. Returns the
+    /// number of bytes discarded.
+    fn discard_input(&mut self) -> Result<u64> {
+        Ok(try!(ignore_timeout(copy(&mut self.port, &mut sink()))))
+    }
+}
+
+
+#[derive(Debug)]
+pub enum Error {
+    /// The monitor doesn't reply, or its replies don't make sense, even after
+    /// trying to resynchronize.
+    NotResponding,
+
+    /// The range of memory with the given address and length extends beyond
+    /// the end of the address space.
+    OutOfRange(u32, u32),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::NotResponding =>
+                write!(f,
+                    "SAM-BA monitor is not responding. Is the device \
+                    connected and running the bootloader?"
+                ),
+            Error::OutOfRange(address, length) =>
+                write!(f,
+                    "Range at 0x{:0>8X} ({} bytes) extends beyond the end of \
+                    the address space",
+                    address, length,
+                ),
+        }
+    }
+}
+
+
+/// Returns the address right after the given range of memory, or
+/// `Error::OutOfRange`, if the range extends beyond the end of the address
+/// space. The result is 64 bits wide, as a range may end at the very end of
+/// the address space.
+fn end_of(address: u32, length: u32) -> Result<u64> {
+    if length == 0 || address.checked_add(length - 1).is_some() {
+        Ok(address as u64 + length as u64)
+    }
+    else {
+        Err(Error::OutOfRange(address, length).into())
+    }
+}
+
+/// Sends the command to read a word and reads the reply.
+fn request_word<P: Read + Write>(port: &mut P, address: u32) -> Result<u32> {
+    try!(write!(port, "w{:0>8X},#", address));
+    let result = try!(port.read_u32::<LittleEndian>());
+
+    Ok(result)
 }
@@ -16,3 +16,21 @@ pub fn init(path: &str) -> serial::Result<serial::SystemPort> {
 
     Ok(port)
 }
+
+/// Opens the port at 1200 baud and closes it again. On the Arduino Due's
+/// programming port, this causes the ATmega16U2 that acts as the USB-to-serial
+/// converter to erase the SAM3X8E and reset it, which makes it start the SAM-BA
+/// bootloader.
+///
+/// This is the same thing the Arduino IDE does before uploading. See the
+/// ATmega16U2 firmware in the Arduino repository for details.
+pub fn touch(path: &str) -> serial::Result<()> {
+    let mut port = try!(serial::open(path));
+
+    try!(port.reconfigure(&|settings| {
+        settings.set_baud_rate(serial::Baud1200)
+    }));
+    try!(port.set_dtr(false));
+
+    Ok(())
+}
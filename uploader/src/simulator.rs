@@ -0,0 +1,888 @@
+// Simulation of the SAM-BA monitor running on a SAM3X8E. It can be used in
+// place of a serial port, to test the uploader without a real device.
+//
+// Only the parts of the hardware the uploader interacts with are simulated:
+// The SRAM, both flash banks and their controllers (see data sheet, chapter
+// 18) and the chip ID registers. Everything else is treated as plain memory.
+// Code can't be executed, so the go command (`G`) only records the address.
+// The exception is the applet (see `applet` module), whose commands are run
+// against the simulated memory, as if it had been loaded.
+
+
+use std::cmp;
+use std::collections::{
+    BTreeMap,
+    VecDeque,
+};
+use std::ffi::{
+    CStr,
+    CString,
+};
+use std::io;
+use std::io::prelude::*;
+use std::mem;
+
+use libc;
+
+use applet;
+use xmodem;
+
+
+const SRAM_ADDR : u32 = 0x20070000;
+const SRAM_SIZE : u32 = 0x18000;
+const SRAM0_ADDR: u32 = 0x20000000;
+const SRAM0_SIZE: u32 = 0x10000;
+
+const FLASH_ADDR      : u32 = 0x00080000;
+const PLANE_SIZE      : u32 = 0x40000;
+const PAGE_SIZE       : u32 = 256;
+const LOCK_REGION_SIZE: u32 = 0x4000;
+
+const EEFC_ADDRS: [u32; 2] = [0x400e0a00, 0x400e0c00];
+
+const CIDR_ADDR: u32 = 0x400e0940;
+const EXID_ADDR: u32 = 0x400e0944;
+const CIDR     : u32 = 0x285e0a60;
+
+const FRDY  : u32 = 0x1;
+const FCMDE : u32 = 0x2;
+const FLOCKE: u32 = 0x4;
+
+const VERSION: &str = "v1.1 Dec 15 2010 19:25:04\n\r";
+
+
+/// A simulated SAM-BA monitor. Commands are sent to it by writing, and its
+/// replies are received by reading. If no reply is available, reads fail with
+/// `io::ErrorKind::TimedOut`, like they would on a serial port.
+pub struct Simulator {
+    state        : State,
+    output       : VecDeque<u8>,
+    terminal_mode: bool,
+    raw_transfers: bool,
+    go_address   : Option<u32>,
+    replies      : u32,
+    lose_reply   : Option<u32>,
+
+    sram     : Vec<u8>,
+    flash    : Vec<u8>,
+    eefcs    : [Eefc; 2],
+    gpnvm    : u32,
+    unique_id: [u8; 16],
+    registers: BTreeMap<u32, u32>,
+}
+
+impl Simulator {
+    /// Creates a simulator with erased flash memory. Like the real monitor,
+    /// it starts out in terminal mode.
+    pub fn new() -> Self {
+        Simulator {
+            state        : State::Command(Vec::new()),
+            output       : VecDeque::new(),
+            terminal_mode: true,
+            raw_transfers: false,
+            go_address   : None,
+            replies      : 0,
+            lose_reply   : None,
+
+            sram     : vec![0; SRAM_SIZE as usize],
+            flash    : vec![0xff; 2 * PLANE_SIZE as usize],
+            eefcs    : [Eefc::new(), Eefc::new()],
+            gpnvm    : 0,
+            unique_id: *b"SIMULATED SAM3X8",
+            registers: BTreeMap::new(),
+        }
+    }
+
+    /// Returns the contents of the whole flash memory.
+    pub fn flash(&self) -> &[u8] {
+        &self.flash
+    }
+
+    /// Returns the GPNVM bits. Bit 0 is GPNVM bit 0, and so forth.
+    pub fn gpnvm_bits(&self) -> u32 {
+        self.gpnvm
+    }
+
+    /// Returns the lock bits of the given flash bank. Bit 0 is the lock bit of
+    /// the first lock region, and so forth.
+    pub fn lock_bits(&self, bank: usize) -> u32 {
+        self.eefcs[bank].lock_bits
+    }
+
+    /// Returns the address that was passed to the last go command, if any.
+    pub fn go_address(&self) -> Option<u32> {
+        self.go_address
+    }
+
+    /// Queues bytes for reading, as if they had been left over from an
+    /// earlier session, to test whether the uploader can deal with that.
+    pub fn inject(&mut self, data: &[u8]) {
+        self.output.extend(data);
+    }
+
+    /// Makes the simulator transfer the data of the send and receive file
+    /// commands as is, instead of using XMODEM, like the monitor does on its
+    /// USB port.
+    pub fn set_raw_transfers(&mut self, raw_transfers: bool) {
+        self.raw_transfers = raw_transfers;
+    }
+
+    /// Makes the simulator lose the last byte of the `n`th reply to a read
+    /// command in normal mode, counting from now, like an unreliable
+    /// connection would. Only that one reply is affected, so the uploader can
+    /// recover by retrying.
+    pub fn lose_reply(&mut self, n: u32) {
+        self.lose_reply = Some(self.replies + n);
+    }
+
+    fn receive(&mut self, byte: u8) {
+        let state = mem::replace(&mut self.state, State::Command(Vec::new()));
+
+        self.state = match state {
+            State::Command(mut command) => {
+                command.push(byte);
+
+                if byte == b'#' {
+                    self.execute(&command);
+
+                    // `execute` might have started a transfer.
+                    return;
+                }
+
+                State::Command(command)
+            },
+
+            State::Receiving(transfer) =>
+                if self.raw_transfers {
+                    self.receive_raw(transfer, byte)
+                }
+                else {
+                    self.receive_xmodem(transfer, byte)
+                },
+
+            State::Sending(transfer) =>
+                self.send_xmodem(transfer, byte),
+        };
+    }
+
+    fn execute(&mut self, command: &[u8]) {
+        // Skip anything that might come before the command, like a stray
+        // line break from a terminal session.
+        let start = command.iter()
+            .position(|byte| b"NTVwhoWHOSRG".contains(byte))
+            .unwrap_or(command.len() - 1);
+
+        let name = command[start];
+        let args: Vec<u32> = String::from_utf8_lossy(&command[start + 1 ..])
+            .trim_end_matches('#')
+            .split(',')
+            .filter_map(|arg| u32::from_str_radix(arg.trim(), 16).ok())
+            .collect();
+
+        let arg = |i: usize| args.get(i).cloned().unwrap_or(0);
+
+        match name {
+            b'N' => {
+                self.terminal_mode = false;
+                self.output.extend(b"\n\r");
+            },
+            b'T' => {
+                self.terminal_mode = true;
+                self.output.extend(b"\n\r>");
+            },
+            b'V' => {
+                self.output.extend(VERSION.as_bytes());
+            },
+
+            b'w' => self.read_command(arg(0), 4),
+            b'h' => self.read_command(arg(0), 2),
+            b'o' => self.read_command(arg(0), 1),
+
+            b'W' => self.write(arg(0), arg(1), 4),
+            b'H' => self.write(arg(0), arg(1), 2),
+            b'O' => self.write(arg(0), arg(1), 1),
+
+            b'S' if self.raw_transfers && arg(1) == 0 => (),
+            b'S' => {
+                if !self.raw_transfers {
+                    self.output.push_back(xmodem::START);
+                }
+                self.state = State::Receiving(Receiving {
+                    address: arg(0),
+                    length : arg(1),
+                    data   : Vec::new(),
+                    packet : Vec::new(),
+                });
+            },
+            b'R' => {
+                let data: Vec<u8> = (0 .. arg(1))
+                    .map(|i| self.read(arg(0).wrapping_add(i), 1) as u8)
+                    .collect();
+
+                if self.raw_transfers {
+                    self.output.extend(data);
+                    return;
+                }
+
+                self.state = State::Sending(Sending {
+                    data : data,
+                    block: None,
+                });
+            },
+
+            b'G' => {
+                self.go_address = Some(arg(0));
+
+                if arg(0) & !0x1 == applet::CODE_ADDR {
+                    self.run_applet();
+                }
+            },
+
+            _ => (),
+        }
+    }
+
+    /// Runs the applet's command, as if the applet had been started. The
+    /// code that was loaded isn't used, but the applet's commands are compiled
+    /// into the uploader, so this still runs the same code as the device.
+    fn run_applet(&mut self) {
+        applet::commands::execute(&mut AppletMemory(self));
+    }
+
+    fn read_command(&mut self, address: u32, size: u32) {
+        let value = self.read(address, size);
+
+        if self.terminal_mode {
+            let text = format!(
+                "\n\r0x{:0>width$X}\n\r>",
+                value, width = size as usize * 2,
+            );
+            self.output.extend(text.as_bytes());
+        }
+        else {
+            self.replies += 1;
+
+            let lost = self.lose_reply == Some(self.replies);
+            let size = if lost { size - 1 } else { size };
+
+            for i in 0 .. size {
+                self.output.push_back((value >> (i * 8)) as u8);
+            }
+        }
+    }
+
+    fn receive_raw(&mut self, mut transfer: Receiving, byte: u8) -> State {
+        transfer.data.push(byte);
+
+        if transfer.data.len() < transfer.length as usize {
+            return State::Receiving(transfer);
+        }
+
+        for (i, &byte) in transfer.data.iter().enumerate() {
+            let address = transfer.address.wrapping_add(i as u32);
+            self.write(address, byte as u32, 1);
+        }
+
+        State::Command(Vec::new())
+    }
+
+    fn receive_xmodem(&mut self, mut transfer: Receiving, byte: u8) -> State {
+        if transfer.packet.is_empty() {
+            match byte {
+                xmodem::SOH => (),
+
+                xmodem::EOT => {
+                    self.output.push_back(xmodem::ACK);
+
+                    for i in 0 .. transfer.length {
+                        let byte = transfer.data.get(i as usize)
+                            .cloned()
+                            .unwrap_or(0);
+                        let address = transfer.address.wrapping_add(i);
+                        self.write(address, byte as u32, 1);
+                    }
+
+                    return State::Command(Vec::new());
+                },
+
+                xmodem::CAN =>
+                    return State::Command(Vec::new()),
+
+                // Ignore anything that's not the start of a packet.
+                _ =>
+                    return State::Receiving(transfer),
+            }
+        }
+
+        transfer.packet.push(byte);
+        if transfer.packet.len() < xmodem::BLOCK_SIZE + 5 {
+            return State::Receiving(transfer);
+        }
+
+        let number = (transfer.data.len() / xmodem::BLOCK_SIZE + 1) as u8;
+
+        {
+            let packet = &transfer.packet;
+            let block  = &packet[3 .. xmodem::BLOCK_SIZE + 3];
+            let crc    =
+                (packet[xmodem::BLOCK_SIZE + 3] as u16) << 8
+                | packet[xmodem::BLOCK_SIZE + 4] as u16;
+
+            if packet[1] == number
+                && packet[2] == !number
+                && xmodem::crc16(block) == crc
+            {
+                transfer.data.extend_from_slice(block);
+                self.output.push_back(xmodem::ACK);
+            }
+            else {
+                self.output.push_back(xmodem::NAK);
+            }
+        }
+
+        transfer.packet.clear();
+        State::Receiving(transfer)
+    }
+
+    fn send_xmodem(&mut self, mut transfer: Sending, byte: u8) -> State {
+        let number_of_blocks =
+            transfer.data.len().div_ceil(xmodem::BLOCK_SIZE);
+
+        let next = match (transfer.block, byte) {
+            (None, xmodem::START)  => 0,
+            (Some(i), xmodem::ACK) => i + 1,
+            (Some(i), xmodem::NAK) => i,
+
+            (_, xmodem::CAN) =>
+                return State::Command(Vec::new()),
+            _ =>
+                return State::Sending(transfer),
+        };
+
+        if next > number_of_blocks {
+            // The receiver acknowledged the end of the transfer.
+            return State::Command(Vec::new());
+        }
+        if next == number_of_blocks {
+            self.output.push_back(xmodem::EOT);
+            transfer.block = Some(next);
+            return State::Sending(transfer);
+        }
+
+        let start = next * xmodem::BLOCK_SIZE;
+        let end   = cmp::min(start + xmodem::BLOCK_SIZE, transfer.data.len());
+
+        let mut block = [0; xmodem::BLOCK_SIZE];
+        block[.. end - start].copy_from_slice(&transfer.data[start .. end]);
+
+        let number = (next + 1) as u8;
+        let crc    = xmodem::crc16(&block);
+
+        self.output.push_back(xmodem::SOH);
+        self.output.push_back(number);
+        self.output.push_back(!number);
+        self.output.extend(block.iter());
+        self.output.push_back((crc >> 8) as u8);
+        self.output.push_back(crc as u8);
+
+        transfer.block = Some(next);
+        State::Sending(transfer)
+    }
+
+    /// Reads a value of the given size (in bytes) from the simulated memory.
+    fn read(&mut self, address: u32, size: u32) -> u32 {
+        if let Some(bank) = eefc_for_register(address) {
+            return self.read_eefc_register(bank, address - EEFC_ADDRS[bank]);
+        }
+
+        match address {
+            CIDR_ADDR => return CIDR,
+            EXID_ADDR => return 0,
+            _         => (),
+        }
+
+        let mut value = 0;
+        for i in 0 .. size {
+            let byte = self.read_byte(address.wrapping_add(i));
+            value |= (byte as u32) << (i * 8);
+        }
+
+        value
+    }
+
+    fn read_byte(&self, address: u32) -> u8 {
+        if let Some(offset) = flash_offset(address) {
+            let bank = (offset / PLANE_SIZE) as usize;
+
+            // While the unique identifier is being read, it replaces the start
+            // of the bank. See data sheet, section 18.4.3.8.
+            if self.eefcs[bank].unique_id_mode {
+                let offset_in_bank = offset % PLANE_SIZE;
+                if offset_in_bank < 16 {
+                    return self.unique_id[offset_in_bank as usize];
+                }
+            }
+
+            return self.flash[offset as usize];
+        }
+        if let Some(offset) = sram_offset(address) {
+            return self.sram[offset as usize];
+        }
+
+        let word = self.registers.get(&(address & !0x3)).cloned().unwrap_or(0);
+        (word >> ((address & 0x3) * 8)) as u8
+    }
+
+    /// Writes a value of the given size (in bytes) to the simulated memory.
+    fn write(&mut self, address: u32, value: u32, size: u32) {
+        if let Some(bank) = eefc_for_register(address) {
+            let offset = address - EEFC_ADDRS[bank];
+            return self.write_eefc_register(bank, offset, value);
+        }
+
+        for i in 0 .. size {
+            self.write_byte(address.wrapping_add(i), (value >> (i * 8)) as u8);
+        }
+    }
+
+    fn write_byte(&mut self, address: u32, byte: u8) {
+        // Writes to the flash memory go to the latch buffer of the bank's
+        // controller. See data sheet, section 18.4.3.2.
+        if let Some(offset) = flash_offset(address) {
+            let bank = (offset / PLANE_SIZE) as usize;
+            self.eefcs[bank].latch[(offset % PAGE_SIZE) as usize] = byte;
+            return;
+        }
+        if let Some(offset) = sram_offset(address) {
+            self.sram[offset as usize] = byte;
+            return;
+        }
+
+        let shift = (address & 0x3) * 8;
+        let word  = self.registers.entry(address & !0x3).or_insert(0);
+        *word = *word & !(0xff << shift) | (byte as u32) << shift;
+    }
+
+    fn read_eefc_register(&mut self, bank: usize, offset: u32) -> u32 {
+        let eefc = &mut self.eefcs[bank];
+
+        match offset {
+            0x00 => eefc.mode,
+            0x08 => {
+                // The error flags are cleared by reading the status register.
+                let status = eefc.status;
+                eefc.status &= !(FCMDE | FLOCKE);
+                status
+            },
+            0x0c => eefc.results.pop_front().unwrap_or(0),
+            _    => 0,
+        }
+    }
+
+    fn write_eefc_register(&mut self, bank: usize, offset: u32, value: u32) {
+        match offset {
+            0x00 => self.eefcs[bank].mode = value,
+            0x04 => self.execute_flash_command(bank, value),
+            _    => (),
+        }
+    }
+
+    /// Executes a command that was written to the command register of the
+    /// given bank's controller. See data sheet, section 18.4.3.
+    fn execute_flash_command(&mut self, bank: usize, command: u32) {
+        let key      = command >> 24;
+        let argument = command >> 8 & 0xffff;
+        let command  = command & 0xff;
+
+        self.eefcs[bank].status  = FRDY;
+        self.eefcs[bank].results.clear();
+
+        if key != 0x5a {
+            self.eefcs[bank].status |= FCMDE;
+            return;
+        }
+
+        let pages_per_bank   = PLANE_SIZE / PAGE_SIZE;
+        let pages_per_region = LOCK_REGION_SIZE / PAGE_SIZE;
+        let number_of_regions = PLANE_SIZE / LOCK_REGION_SIZE;
+
+        let region = argument / pages_per_region;
+
+        match command {
+            // Get Flash Descriptor
+            0x00 => {
+                let eefc = &mut self.eefcs[bank];
+
+                eefc.results.extend(&[
+                    0x00000000,
+                    PLANE_SIZE,
+                    PAGE_SIZE,
+                    1,
+                    PLANE_SIZE,
+                    number_of_regions,
+                ]);
+                for _ in 0 .. number_of_regions {
+                    eefc.results.push_back(LOCK_REGION_SIZE);
+                }
+            },
+
+            // Write Page, Write Page and Lock, Erase Page and Write Page,
+            // Erase Page and Write Page then Lock
+            0x01 ..= 0x04 => {
+                if argument >= pages_per_bank {
+                    self.eefcs[bank].status |= FCMDE;
+                    return;
+                }
+                if self.eefcs[bank].lock_bits & 1 << region != 0 {
+                    self.eefcs[bank].status |= FLOCKE;
+                    return;
+                }
+
+                let start = (bank as u32 * PLANE_SIZE + argument * PAGE_SIZE)
+                    as usize;
+                let erase = command == 0x03 || command == 0x04;
+
+                let eefc = &mut self.eefcs[bank];
+                for (i, &byte) in eefc.latch.iter().enumerate() {
+                    // Without erasing, programming can only clear bits.
+                    if erase {
+                        self.flash[start + i] = byte;
+                    }
+                    else {
+                        self.flash[start + i] &= byte;
+                    }
+                }
+
+                for byte in eefc.latch.iter_mut() {
+                    *byte = 0xff;
+                }
+
+                if command == 0x02 || command == 0x04 {
+                    eefc.lock_bits |= 1 << region;
+                }
+            },
+
+            // Erase All
+            0x05 => {
+                if self.eefcs[bank].lock_bits != 0 {
+                    self.eefcs[bank].status |= FLOCKE;
+                    return;
+                }
+
+                let start = (bank as u32 * PLANE_SIZE) as usize;
+                let end   = start + PLANE_SIZE as usize;
+                for byte in &mut self.flash[start .. end] {
+                    *byte = 0xff;
+                }
+            },
+
+            // Set Lock Bit, Clear Lock Bit
+            0x08 | 0x09 if argument < pages_per_bank => {
+                if command == 0x08 {
+                    self.eefcs[bank].lock_bits |= 1 << region;
+                }
+                else {
+                    self.eefcs[bank].lock_bits &= !(1 << region);
+                }
+            },
+
+            // Get Lock Bit
+            0x0a => {
+                let lock_bits = self.eefcs[bank].lock_bits;
+                self.eefcs[bank].results.push_back(lock_bits);
+            },
+
+            // Set GPNVM Bit, Clear GPNVM Bit. Only the first controller is
+            // responsible for the GPNVM bits, and the security bit can't be
+            // cleared.
+            0x0b | 0x0c if bank == 0 && argument < 3 => {
+                if command == 0x0b {
+                    self.gpnvm |= 1 << argument;
+                }
+                else if argument != 0 {
+                    self.gpnvm &= !(1 << argument);
+                }
+                else {
+                    self.eefcs[bank].status |= FCMDE;
+                }
+            },
+
+            // Get GPNVM Bit
+            0x0d if bank == 0 => {
+                let gpnvm = self.gpnvm;
+                self.eefcs[bank].results.push_back(gpnvm);
+            },
+
+            // Start Read Unique Identifier. The controller isn't ready until
+            // the Stop Read Unique Identifier command has been executed.
+            0x0e => {
+                self.eefcs[bank].unique_id_mode = true;
+                self.eefcs[bank].status        = 0;
+            },
+
+            // Stop Read Unique Identifier
+            0x0f => {
+                self.eefcs[bank].unique_id_mode = false;
+            },
+
+            _ => {
+                self.eefcs[bank].status |= FCMDE;
+            },
+        }
+    }
+}
+
+impl Default for Simulator {
+    fn default() -> Self {
+        Simulator::new()
+    }
+}
+
+impl Read for Simulator {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.output.is_empty() && !buf.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                "Simulated monitor has nothing to send",
+            ));
+        }
+
+        let mut n = 0;
+        while n < buf.len() {
+            match self.output.pop_front() {
+                Some(byte) => buf[n] = byte,
+                None       => break,
+            }
+            n += 1;
+        }
+
+        Ok(n)
+    }
+}
+
+impl Write for Simulator {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        for &byte in buf {
+            self.receive(byte);
+        }
+
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+
+/// The memory, as the applet sees it when it runs.
+struct AppletMemory<'r>(&'r mut Simulator);
+
+impl<'r> applet::commands::Memory for AppletMemory<'r> {
+    fn read(&mut self, address: u32) -> u32 {
+        self.0.read(address, 4)
+    }
+
+    fn write(&mut self, address: u32, value: u32) {
+        self.0.write(address, value, 4)
+    }
+
+    fn read_byte(&mut self, address: u32) -> u8 {
+        self.0.read_byte(address)
+    }
+}
+
+
+enum State {
+    /// Waiting for a command to be completed by '#'.
+    Command(Vec<u8>),
+
+    /// Receiving data for the send file command.
+    Receiving(Receiving),
+
+    /// Sending data for the receive file command.
+    Sending(Sending),
+}
+
+struct Receiving {
+    address: u32,
+    length : u32,
+    data   : Vec<u8>,
+    packet : Vec<u8>,
+}
+
+struct Sending {
+    data : Vec<u8>,
+
+    /// The index of the last block that was sent. `None`, if the receiver
+    /// hasn't requested the transfer yet.
+    block: Option<usize>,
+}
+
+
+struct Eefc {
+    mode          : u32,
+    status        : u32,
+    results       : VecDeque<u32>,
+    latch         : Vec<u8>,
+    lock_bits     : u32,
+    unique_id_mode: bool,
+}
+
+impl Eefc {
+    fn new() -> Self {
+        Eefc {
+            mode          : 0,
+            status        : FRDY,
+            results       : VecDeque::new(),
+            latch         : vec![0xff; PAGE_SIZE as usize],
+            lock_bits     : 0,
+            unique_id_mode: false,
+        }
+    }
+}
+
+
+fn eefc_for_register(address: u32) -> Option<usize> {
+    EEFC_ADDRS
+        .iter()
+        .position(|&base| address >= base && address < base + 0x10)
+}
+
+fn flash_offset(address: u32) -> Option<u32> {
+    if address >= FLASH_ADDR && address - FLASH_ADDR < 2 * PLANE_SIZE {
+        Some(address - FLASH_ADDR)
+    }
+    else {
+        None
+    }
+}
+
+/// Returns the offset into the simulated SRAM. The first SRAM block is also
+/// mapped to its own address range. See data sheet, section 7.2.1.
+fn sram_offset(address: u32) -> Option<u32> {
+    if address >= SRAM_ADDR && address - SRAM_ADDR < SRAM_SIZE {
+        Some(address - SRAM_ADDR)
+    }
+    else if address >= SRAM0_ADDR && address - SRAM0_ADDR < SRAM0_SIZE {
+        Some(address - SRAM0_ADDR)
+    }
+    else {
+        None
+    }
+}
+
+
+/// A Linux pseudo-terminal that forwards everything written to its slave side
+/// to a simulator, and the simulator's replies back. This allows the uploader
+/// binary to be pointed at the simulator, as if it were a real device.
+pub struct Pty {
+    master    : libc::c_int,
+    slave     : libc::c_int,
+    slave_path: String,
+}
+
+impl Pty {
+    pub fn open() -> io::Result<Pty> {
+        unsafe {
+            let master = libc::posix_openpt(libc::O_RDWR | libc::O_NOCTTY);
+            if master < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            if libc::grantpt(master) < 0 || libc::unlockpt(master) < 0 {
+                let error = io::Error::last_os_error();
+                libc::close(master);
+                return Err(error);
+            }
+
+            let name = libc::ptsname(master);
+            if name.is_null() {
+                let error = io::Error::last_os_error();
+                libc::close(master);
+                return Err(error);
+            }
+            let slave_path =
+                CStr::from_ptr(name).to_string_lossy().into_owned();
+
+            // We keep the slave side open ourselves. Otherwise reading from
+            // the master would fail, whenever no client has it open.
+            let path  = CString::new(slave_path.clone()).unwrap();
+            let slave =
+                libc::open(path.as_ptr(), libc::O_RDWR | libc::O_NOCTTY);
+            if slave < 0 {
+                let error = io::Error::last_os_error();
+                libc::close(master);
+                return Err(error);
+            }
+
+            // The data is binary, so the terminal must not interpret it.
+            let mut termios = mem::zeroed();
+            if libc::tcgetattr(slave, &mut termios) == 0 {
+                libc::cfmakeraw(&mut termios);
+                libc::tcsetattr(slave, libc::TCSANOW, &termios);
+            }
+
+            Ok(Pty {
+                master    : master,
+                slave     : slave,
+                slave_path: slave_path,
+            })
+        }
+    }
+
+    /// Returns the path of the slave side, e.g. `/dev/pts/3`.
+    pub fn slave_path(&self) -> &str {
+        &self.slave_path
+    }
+
+    /// Forwards data between the pseudo-terminal and the simulator. Never
+    /// returns, unless an error occurs.
+    pub fn serve(&mut self, simulator: &mut Simulator) -> io::Result<()> {
+        let mut buffer = [0; 1024];
+
+        loop {
+            let n = unsafe {
+                libc::read(
+                    self.master,
+                    buffer.as_mut_ptr() as *mut libc::c_void,
+                    buffer.len(),
+                )
+            };
+            if n < 0 {
+                let error = io::Error::last_os_error();
+                if error.kind() == io::ErrorKind::Interrupted {
+                    continue;
+                }
+                return Err(error);
+            }
+
+            try!(simulator.write_all(&buffer[.. n as usize]));
+
+            let output: Vec<u8> = simulator.output.drain(..).collect();
+            let mut written = 0;
+            while written < output.len() {
+                let n = unsafe {
+                    libc::write(
+                        self.master,
+                        output[written ..].as_ptr() as *const libc::c_void,
+                        output.len() - written,
+                    )
+                };
+                if n < 0 {
+                    return Err(io::Error::last_os_error());
+                }
+
+                written += n as usize;
+            }
+        }
+    }
+}
+
+impl Drop for Pty {
+    fn drop(&mut self) {
+        unsafe {
+            libc::close(self.slave);
+            libc::close(self.master);
+        }
+    }
+}
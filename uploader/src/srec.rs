@@ -0,0 +1,158 @@
+// Loads images from files in the Motorola S-record format, as produced by
+// `objcopy -O srec`, for example.
+
+
+use image::Image;
+use utils::parse_hex_bytes;
+
+
+/// Creates an image from a file in the S-record format. Regions that aren't
+/// covered by any data record are not part of the image.
+pub fn parse(text: &str) -> Result<Image, Error> {
+    let mut image = Image::empty();
+
+    for (i, line) in text.lines().enumerate() {
+        let line_number = i + 1;
+
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        if !line.starts_with('S') {
+            return Err(Error::InvalidRecord(line_number));
+        }
+
+        let record_type = try!(
+            line.get(1..2).ok_or(Error::InvalidRecord(line_number))
+        );
+        let bytes = try!(
+            line.get(2..)
+                .and_then(parse_hex_bytes)
+                .ok_or(Error::InvalidRecord(line_number))
+        );
+
+        // The first byte is the number of bytes that follow it.
+        if bytes.len() < 2 || bytes.len() != bytes[0] as usize + 1 {
+            return Err(Error::InvalidRecord(line_number));
+        }
+
+        // The checksum is the one's complement of the sum of all other bytes.
+        let sum = bytes.iter().fold(0u8, |sum, &byte| sum.wrapping_add(byte));
+        if sum != 0xff {
+            return Err(Error::Checksum(line_number));
+        }
+
+        let address_size = match record_type {
+            "1" | "9" => 2,
+            "2" | "8" => 3,
+            "3" | "7" => 4,
+
+            // Header (S0) and record count (S5, S6) records don't contain
+            // anything we need.
+            "0" | "5" | "6" => continue,
+
+            _ => return Err(Error::InvalidRecord(line_number)),
+        };
+
+        if bytes.len() < address_size + 2 {
+            return Err(Error::InvalidRecord(line_number));
+        }
+
+        let address = bytes[1 .. address_size + 1]
+            .iter()
+            .fold(0, |address, &byte| address << 8 | byte as u32);
+        let data = &bytes[address_size + 1 .. bytes.len() - 1];
+
+        match record_type {
+            "1" | "2" | "3" =>
+                try!(
+                    image.add(address, data)
+                        .map_err(|_| Error::OutOfRange(line_number))
+                ),
+
+            // The termination records contain the start address, which we
+            // don't need. The microcontroller takes its start address from the
+            // vector table.
+            _ =>
+                return Ok(image),
+        }
+    }
+
+    Ok(image)
+}
+
+
+#[derive(Debug)]
+pub enum Error {
+    /// The record on the given line is malformed or of an unknown type.
+    InvalidRecord(usize),
+
+    /// The checksum of the record on the given line doesn't match.
+    Checksum(usize),
+
+    /// The data of the record on the given line extends beyond the end of the
+    /// address space.
+    OutOfRange(usize),
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        parse,
+        Error,
+    };
+
+
+    #[test]
+    fn parses_data_records() {
+        let image = parse(
+            "S00700007465737438\n\
+             S10500000102F7\n\
+             S3090008FFFEAABBCCDDE3\n\
+             S70500000000FA\n"
+        ).unwrap();
+
+        // Records may cross 64K boundaries, as they carry the full address.
+        let segments = image.segments();
+        assert_eq!(segments.len(), 2);
+        assert_eq!(segments[0].address, 0x00000000);
+        assert_eq!(segments[0].data, [0x01, 0x02]);
+        assert_eq!(segments[1].address, 0x0008FFFE);
+        assert_eq!(segments[1].data, [0xAA, 0xBB, 0xCC, 0xDD]);
+    }
+
+    #[test]
+    fn stops_at_the_termination_record() {
+        let image = parse("S70500000000FA\nS10500000102F7\n").unwrap();
+        assert!(image.segments().is_empty());
+    }
+
+    #[test]
+    fn rejects_bad_checksums() {
+        match parse("S10500000102F8\n") {
+            Err(Error::Checksum(1)) => (),
+            result => panic!("Unexpected result: {:?}", result.err()),
+        }
+    }
+
+    #[test]
+    fn rejects_unsupported_record_types() {
+        match parse("S00700007465737438\nS4030000FC\n") {
+            Err(Error::InvalidRecord(2)) => (),
+            result => panic!("Unexpected result: {:?}", result.err()),
+        }
+    }
+
+    #[test]
+    fn rejects_truncated_input() {
+        match parse("S10500000102F7\nS3090008FFFEAABB\n") {
+            Err(Error::InvalidRecord(2)) => (),
+            result => panic!("Unexpected result: {:?}", result.err()),
+        }
+        match parse("S1\n") {
+            Err(Error::InvalidRecord(1)) => (),
+            result => panic!("Unexpected result: {:?}", result.err()),
+        }
+    }
+}
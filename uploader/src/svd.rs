@@ -0,0 +1,547 @@
+// Support for System View Description (SVD) files, as defined by ARM's CMSIS.
+// Atmel provides one for each of its microcontrollers, describing the
+// registers of all peripherals, down to the meaning of the values of each
+// field. Only what's needed to decode register values is read.
+//
+// See http://www.keil.com/pack/doc/CMSIS/SVD/html/svd_Format_pg.html
+
+
+use std::fmt;
+use std::fs::File;
+use std::io::prelude::*;
+use std::path::Path;
+
+use result;
+use utils::parse_u32;
+use xml;
+use xml::Element;
+
+
+/// The description of a microcontroller.
+pub struct Device {
+    pub name       : String,
+    pub peripherals: Vec<Peripheral>,
+}
+
+impl Device {
+    pub fn load<P: AsRef<Path>>(path: P) -> result::Result<Device> {
+        let mut document = String::new();
+        let mut file     = try!(File::open(path));
+        try!(file.read_to_string(&mut document));
+
+        Ok(try!(Device::parse(&document)))
+    }
+
+    pub fn parse(document: &str) -> Result<Device, Error> {
+        let device = try!(xml::parse(document));
+
+        // These apply to all registers, unless overridden by the peripheral
+        // or the register.
+        let size     = try!(optional_number(&device, "size")).unwrap_or(32);
+        let readable = is_readable(&device, true);
+
+        let mut peripherals = Vec::new();
+        if let Some(element) = device.child("peripherals") {
+            for peripheral in element.children("peripheral") {
+                peripherals.push(
+                    try!(parse_peripheral(peripheral, size, readable))
+                );
+            }
+        }
+
+        // Peripherals that exist more than once, like the PIO controllers,
+        // are only described once. The other instances refer to that.
+        for i in 0 .. peripherals.len() {
+            let base = match peripherals[i].derived_from {
+                Some(ref base) if peripherals[i].registers.is_empty() =>
+                    base.clone(),
+                _ =>
+                    continue,
+            };
+
+            let registers = try!(
+                peripherals
+                    .iter()
+                    .find(|peripheral| peripheral.name == base)
+                    .map(|peripheral| peripheral.registers.clone())
+                    .ok_or(Error::UnknownBase(base))
+            );
+            peripherals[i].registers = registers;
+        }
+
+        Ok(Device {
+            name       : device.child_text("name").unwrap_or("").to_string(),
+            peripherals: peripherals,
+        })
+    }
+
+    /// Returns the peripheral with the given name, ignoring case.
+    pub fn peripheral(&self, name: &str) -> Option<&Peripheral> {
+        self.peripherals
+            .iter()
+            .find(|peripheral| peripheral.name.eq_ignore_ascii_case(name))
+    }
+}
+
+
+pub struct Peripheral {
+    pub name        : String,
+    pub base_address: u32,
+    pub registers   : Vec<Register>,
+
+    derived_from: Option<String>,
+}
+
+
+#[derive(Clone)]
+pub struct Register {
+    pub name: String,
+
+    /// The register's address, relative to the peripheral's base address.
+    pub offset: u32,
+
+    /// The register's size in bits.
+    pub size: u32,
+
+    /// Whether the register can be read without side effects. Write-only
+    /// registers and those that change when read aren't.
+    pub readable: bool,
+
+    pub fields: Vec<Field>,
+}
+
+
+#[derive(Clone)]
+pub struct Field {
+    pub name  : String,
+    pub offset: u32,
+    pub width : u32,
+
+    /// The named values of the field.
+    pub values: Vec<(String, u32)>,
+
+    /// The name of all values not in `values`, if the field has one.
+    pub default: Option<String>,
+}
+
+impl Field {
+    /// Extracts the field's value from the register's value.
+    pub fn extract(&self, register: u32) -> u32 {
+        let mask = if self.width >= 32 { !0 } else { (1 << self.width) - 1 };
+        register >> self.offset & mask
+    }
+
+    /// Returns the name of the value, if it has one.
+    pub fn name_of(&self, value: u32) -> Option<&str> {
+        self.values
+            .iter()
+            .find(|&&(_, v)| v == value)
+            .map(|(name, _)| name.as_ref())
+            .or(self.default.as_ref().map(|name| name.as_ref()))
+    }
+}
+
+
+#[derive(Debug)]
+pub enum Error {
+    Xml(xml::Error),
+
+    /// A required element is missing. Contains its name and the name of the
+    /// element that should contain it.
+    MissingElement(&'static str, String),
+
+    /// A number can't be parsed.
+    InvalidNumber(String),
+
+    /// A peripheral is derived from one that doesn't exist.
+    UnknownBase(String),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::Xml(ref error) =>
+                write!(f, "Invalid XML: {:?}", error),
+            Error::MissingElement(name, ref parent) =>
+                write!(f, "Missing <{}> in {}", name, parent),
+            Error::InvalidNumber(ref number) =>
+                write!(f, "Invalid number: {}", number),
+            Error::UnknownBase(ref name) =>
+                write!(f, "Derived from unknown peripheral {}", name),
+        }
+    }
+}
+
+impl From<xml::Error> for Error {
+    fn from(error: xml::Error) -> Self {
+        Error::Xml(error)
+    }
+}
+
+
+fn parse_peripheral(element: &Element, size: u32, readable: bool)
+    -> Result<Peripheral, Error>
+{
+    let name = try!(required_text(element, "name", "<peripheral>")).to_string();
+
+    let size     = try!(optional_number(element, "size")).unwrap_or(size);
+    let readable = is_readable(element, readable);
+
+    let mut registers = Vec::new();
+    if let Some(element) = element.child("registers") {
+        for register in element.children("register") {
+            registers.extend(try!(parse_register(register, size, readable)));
+        }
+    }
+
+    Ok(Peripheral {
+        base_address: try!(required_number(element, "baseAddress", &name)),
+        registers   : registers,
+        derived_from: element.attribute("derivedFrom").map(String::from),
+        name        : name,
+    })
+}
+
+/// Parses a register. Returns more than one, if the element describes an
+/// array of registers.
+fn parse_register(element: &Element, size: u32, readable: bool)
+    -> Result<Vec<Register>, Error>
+{
+    let name = try!(required_text(element, "name", "<register>")).to_string();
+
+    let mut fields = Vec::new();
+    if let Some(element) = element.child("fields") {
+        for field in element.children("field") {
+            fields.push(try!(parse_field(field, &name)));
+        }
+    }
+
+    let register = Register {
+        offset  : try!(required_number(element, "addressOffset", &name)),
+        size    : try!(optional_number(element, "size")).unwrap_or(size),
+        readable: is_readable(element, readable)
+            && element.child("readAction").is_none(),
+        fields  : fields,
+        name    : name,
+    };
+
+    let dim = match try!(optional_number(element, "dim")) {
+        Some(dim) => dim,
+        None      => return Ok(vec![register]),
+    };
+    let increment =
+        try!(required_number(element, "dimIncrement", &register.name));
+
+    // The index is either a range, like "0-3", or a list, like "A,B,C".
+    let indices: Vec<String> = match element.child_text("dimIndex") {
+        Some(index) => match parse_pair(index, '-') {
+            Some((first, last)) =>
+                (first .. last + 1).map(|i| i.to_string()).collect(),
+            None =>
+                index.split(',').map(|i| i.trim().to_string()).collect(),
+        },
+        None =>
+            (0 .. dim).map(|i| i.to_string()).collect(),
+    };
+
+    Ok(
+        indices
+            .iter()
+            .enumerate()
+            .map(|(i, index)| Register {
+                name  : register.name.replace("%s", index),
+                offset: register.offset + i as u32 * increment,
+                .. register.clone()
+            })
+            .collect()
+    )
+}
+
+fn parse_field(element: &Element, register: &str) -> Result<Field, Error> {
+    let name = try!(required_text(element, "name", register)).to_string();
+
+    // The position can be given in three different ways.
+    let (offset, width) = if element.child("bitOffset").is_some() {
+        (
+            try!(required_number(element, "bitOffset", &name)),
+            try!(optional_number(element, "bitWidth")).unwrap_or(1),
+        )
+    }
+    else if element.child("lsb").is_some() {
+        let lsb = try!(required_number(element, "lsb", &name));
+        let msb = try!(required_number(element, "msb", &name));
+
+        if msb < lsb {
+            return Err(Error::InvalidNumber(format!("[{}:{}]", msb, lsb)));
+        }
+        (lsb, msb - lsb + 1)
+    }
+    else {
+        let range = try!(required_text(element, "bitRange", &name));
+
+        match parse_pair(range.trim_matches(|c| c == '[' || c == ']'), ':') {
+            Some((msb, lsb)) if msb >= lsb =>
+                (lsb, msb - lsb + 1),
+            _ =>
+                return Err(Error::InvalidNumber(range.to_string())),
+        }
+    };
+
+    let mut values  = Vec::new();
+    let mut default = None;
+
+    for enumerated_values in element.children("enumeratedValues") {
+        // Values that only apply when writing don't help with decoding.
+        if enumerated_values.child_text("usage") == Some("write") {
+            continue;
+        }
+
+        for value in enumerated_values.children("enumeratedValue") {
+            let value_name =
+                try!(required_text(value, "name", &name)).to_string();
+
+            if value.child_text("isDefault") == Some("true") {
+                default = Some(value_name);
+                continue;
+            }
+
+            let number = try!(required_text(value, "value", &value_name));
+
+            // Binary values can contain "don't care" bits, which we don't
+            // support.
+            if number.starts_with('#') && number.contains('x') {
+                continue;
+            }
+
+            values.push((value_name, try!(parse_number(number))));
+        }
+    }
+
+    Ok(Field {
+        name   : name,
+        offset : offset,
+        width  : width,
+        values : values,
+        default: default,
+    })
+}
+
+
+/// Returns whether registers are readable according to the element's access
+/// property, or the inherited value, if it doesn't have one.
+fn is_readable(element: &Element, inherited: bool) -> bool {
+    match element.child_text("access") {
+        Some("write-only") | Some("writeOnce") => false,
+        Some(_)                                => true,
+        None                                   => inherited,
+    }
+}
+
+fn required_text<'a>(element: &'a Element, name: &'static str, parent: &str)
+    -> Result<&'a str, Error>
+{
+    element
+        .child_text(name)
+        .ok_or(Error::MissingElement(name, parent.to_string()))
+}
+
+fn required_number(element: &Element, name: &'static str, parent: &str)
+    -> Result<u32, Error>
+{
+    parse_number(try!(required_text(element, name, parent)))
+}
+
+fn optional_number(element: &Element, name: &str)
+    -> Result<Option<u32>, Error>
+{
+    match element.child_text(name) {
+        Some(number) => parse_number(number).map(Some),
+        None         => Ok(None),
+    }
+}
+
+/// Parses a number in one of the formats SVD allows: decimal, hexadecimal
+/// with "0x" prefix, or binary with "#" prefix.
+fn parse_number(number: &str) -> Result<u32, Error> {
+    let number = number.trim();
+
+    let result = if let Some(binary) = number.strip_prefix('#') {
+        u32::from_str_radix(binary, 2)
+    }
+    else {
+        parse_u32(number)
+    };
+
+    result.map_err(|_| Error::InvalidNumber(number.to_string()))
+}
+
+/// Parses two numbers separated by the given character, like "7:0".
+fn parse_pair(s: &str, separator: char) -> Option<(u32, u32)> {
+    let mut numbers = s.splitn(2, separator).map(parse_number);
+
+    match (numbers.next(), numbers.next()) {
+        (Some(Ok(first)), Some(Ok(second))) => Some((first, second)),
+        _                                   => None,
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        Device,
+        Error,
+    };
+
+
+    /// Wraps fields into a device with a single register.
+    fn device_with_fields(fields: &str) -> Result<Device, Error> {
+        Device::parse(&format!(
+            "<device>\
+                <peripherals>\
+                    <peripheral>\
+                        <name>PMC</name>\
+                        <baseAddress>0x400E0600</baseAddress>\
+                        <registers>\
+                            <register>\
+                                <name>PMC_SR</name>\
+                                <addressOffset>0x68</addressOffset>\
+                                <fields>{}</fields>\
+                            </register>\
+                        </registers>\
+                    </peripheral>\
+                </peripherals>\
+            </device>",
+            fields,
+        ))
+    }
+
+
+    #[test]
+    fn parses_peripherals_registers_and_fields() {
+        let device = Device::parse(
+            "<device>\
+                <name>ATSAM3X8E</name>\
+                <access>read-write</access>\
+                <peripherals>\
+                    <peripheral>\
+                        <name>PIOA</name>\
+                        <baseAddress>0x400E0E00</baseAddress>\
+                        <registers>\
+                            <register>\
+                                <dim>2</dim>\
+                                <dimIncrement>0x10</dimIncrement>\
+                                <dimIndex>A,B</dimIndex>\
+                                <name>ABCDSR%s</name>\
+                                <addressOffset>0x70</addressOffset>\
+                            </register>\
+                            <register>\
+                                <name>PIO_CODR</name>\
+                                <addressOffset>0x34</addressOffset>\
+                                <access>write-only</access>\
+                            </register>\
+                        </registers>\
+                    </peripheral>\
+                    <peripheral derivedFrom=\"PIOA\">\
+                        <name>PIOB</name>\
+                        <baseAddress>0x400E1000</baseAddress>\
+                    </peripheral>\
+                </peripherals>\
+            </device>"
+        ).unwrap();
+
+        assert_eq!(device.name, "ATSAM3X8E");
+
+        let pioa = device.peripheral("pioa").unwrap();
+        let registers: Vec<_> = pioa.registers
+            .iter()
+            .map(|register| {
+                (register.name.as_ref(), register.offset, register.readable)
+            })
+            .collect();
+        assert_eq!(registers, [
+            ("ABCDSRA", 0x70, true),
+            ("ABCDSRB", 0x80, true),
+            ("PIO_CODR", 0x34, false),
+        ]);
+
+        let piob = device.peripheral("PIOB").unwrap();
+        assert_eq!(piob.base_address, 0x400E1000);
+        assert_eq!(piob.registers.len(), 3);
+    }
+
+    #[test]
+    fn parses_all_kinds_of_bit_positions() {
+        let device = device_with_fields(
+            "<field>\
+                <name>MOSCXTS</name>\
+                <bitOffset>0</bitOffset>\
+                <enumeratedValues>\
+                    <enumeratedValue>\
+                        <name>STABLE</name>\
+                        <value>#1</value>\
+                    </enumeratedValue>\
+                    <enumeratedValue>\
+                        <name>NOT_STABLE</name>\
+                        <isDefault>true</isDefault>\
+                    </enumeratedValue>\
+                </enumeratedValues>\
+            </field>\
+            <field><name>CSS</name><lsb>4</lsb><msb>5</msb></field>\
+            <field><name>FOS</name><bitRange>[23:20]</bitRange></field>"
+        ).unwrap();
+
+        let fields = &device.peripherals[0].registers[0].fields;
+        let positions: Vec<_> = fields
+            .iter()
+            .map(|field| (field.name.as_ref(), field.offset, field.width))
+            .collect();
+        assert_eq!(positions, [
+            ("MOSCXTS", 0, 1),
+            ("CSS", 4, 2),
+            ("FOS", 20, 4),
+        ]);
+
+        assert_eq!(fields[0].name_of(1), Some("STABLE"));
+        assert_eq!(fields[0].name_of(0), Some("NOT_STABLE"));
+        assert_eq!(fields[1].extract(0x00000030), 3);
+        assert_eq!(fields[2].extract(0x00A00000), 0xA);
+    }
+
+    #[test]
+    fn rejects_fields_whose_msb_is_below_their_lsb() {
+        let fields = [
+            "<field><name>F</name><lsb>5</lsb><msb>4</msb></field>",
+            "<field><name>F</name><bitRange>[4:5]</bitRange></field>",
+        ];
+
+        for &field in &fields {
+            match device_with_fields(field) {
+                Err(Error::InvalidNumber(_)) => (),
+                result => panic!("Unexpected result: {:?}", result.err()),
+            }
+        }
+    }
+
+    #[test]
+    fn rejects_missing_elements_and_unknown_bases() {
+        match device_with_fields("<field><bitOffset>0</bitOffset></field>") {
+            Err(Error::MissingElement("name", ref parent))
+                if parent == "PMC_SR" => (),
+            result => panic!("Unexpected result: {:?}", result.err()),
+        }
+
+        let result = Device::parse(
+            "<device><peripherals>\
+                <peripheral derivedFrom=\"PIOZ\">\
+                    <name>PIOB</name>\
+                    <baseAddress>0x400E1000</baseAddress>\
+                </peripheral>\
+            </peripherals></device>"
+        );
+        match result {
+            Err(Error::UnknownBase(ref name)) if name == "PIOZ" => (),
+            result => panic!("Unexpected result: {:?}", result.err()),
+        }
+    }
+}
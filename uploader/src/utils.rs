@@ -1,4 +1,5 @@
 use std::io;
+use std::num::ParseIntError;
 
 
 pub fn ignore_timeout<T: Default>(result: io::Result<T>) -> io::Result<T> {
@@ -10,3 +11,33 @@ pub fn ignore_timeout<T: Default>(result: io::Result<T>) -> io::Result<T> {
 
     result
 }
+
+
+/// Parses a number that is either given in decimal or, if prefixed with "0x",
+/// in hexadecimal notation.
+pub fn parse_u32(s: &str) -> Result<u32, ParseIntError> {
+    if s.starts_with("0x") || s.starts_with("0X") {
+        u32::from_str_radix(&s[2..], 16)
+    }
+    else {
+        s.parse()
+    }
+}
+
+/// Decodes a string of hexadecimal digit pairs into bytes. Returns `None`, if
+/// the string contains anything else.
+pub fn parse_hex_bytes(s: &str) -> Option<Vec<u8>> {
+    if s.len() % 2 != 0 {
+        return None;
+    }
+
+    let mut bytes = Vec::with_capacity(s.len() / 2);
+    for i in 0 .. s.len() / 2 {
+        match s.get(i * 2 .. i * 2 + 2).map(|s| u8::from_str_radix(s, 16)) {
+            Some(Ok(byte)) => bytes.push(byte),
+            _              => return None,
+        }
+    }
+
+    Some(bytes)
+}
@@ -0,0 +1,316 @@
+// Minimal support for reading XML documents, as needed for SVD files (see
+// `svd` module). The whole document is read into a tree of elements.
+// Namespaces, DTDs and character references beyond the predefined entities
+// aren't supported.
+
+
+/// An element, with its attributes, child elements and text content.
+#[derive(Debug)]
+pub struct Element {
+    pub name      : String,
+    pub attributes: Vec<(String, String)>,
+    pub children  : Vec<Element>,
+
+    /// The text directly contained in the element, without that of its
+    /// children, and with leading and trailing whitespace removed.
+    pub text: String,
+}
+
+impl Element {
+    /// Returns the first child element with the given name.
+    pub fn child(&self, name: &str) -> Option<&Element> {
+        self.children.iter().find(|child| child.name == name)
+    }
+
+    /// Returns all child elements with the given name.
+    pub fn children(&self, name: &str) -> Vec<&Element> {
+        self.children.iter().filter(|child| child.name == name).collect()
+    }
+
+    /// Returns the text of the first child element with the given name.
+    pub fn child_text(&self, name: &str) -> Option<&str> {
+        self.child(name).map(|child| child.text.as_ref())
+    }
+
+    pub fn attribute(&self, name: &str) -> Option<&str> {
+        self.attributes
+            .iter()
+            .find(|(attribute, _)| attribute == name)
+            .map(|(_, value)| value.as_ref())
+    }
+}
+
+
+/// Parses a document and returns its root element.
+pub fn parse(document: &str) -> Result<Element, Error> {
+    let mut parser = Parser {
+        input   : document,
+        position: 0,
+    };
+
+    try!(parser.skip_misc());
+    let root = try!(parser.element());
+    try!(parser.skip_misc());
+
+    if parser.position < document.len() {
+        return Err(Error::Syntax(parser.position));
+    }
+
+    Ok(root)
+}
+
+
+#[derive(Debug)]
+pub enum Error {
+    /// The document is malformed at the given byte offset.
+    Syntax(usize),
+
+    /// The document ended in the middle of something.
+    UnexpectedEnd,
+
+    /// An end tag doesn't match the start tag. Contains both names.
+    MismatchedTag(String, String),
+}
+
+
+struct Parser<'a> {
+    input   : &'a str,
+    position: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn rest(&self) -> &'a str {
+        &self.input[self.position ..]
+    }
+
+    /// Skips whitespace, comments, processing instructions (like the XML
+    /// declaration) and document type declarations.
+    fn skip_misc(&mut self) -> Result<(), Error> {
+        loop {
+            let trimmed = self.rest().trim_start();
+            self.position = self.input.len() - trimmed.len();
+
+            if trimmed.starts_with("<?") {
+                try!(self.skip_past("?>"));
+            }
+            else if trimmed.starts_with("<!--") {
+                try!(self.skip_past("-->"));
+            }
+            else if trimmed.starts_with("<!") {
+                try!(self.skip_past(">"));
+            }
+            else {
+                return Ok(());
+            }
+        }
+    }
+
+    fn skip_past(&mut self, end: &str) -> Result<(), Error> {
+        match self.rest().find(end) {
+            Some(index) => {
+                self.position += index + end.len();
+                Ok(())
+            },
+            None =>
+                Err(Error::UnexpectedEnd),
+        }
+    }
+
+    fn expect(&mut self, s: &str) -> Result<(), Error> {
+        if !self.rest().starts_with(s) {
+            if self.rest().is_empty() {
+                return Err(Error::UnexpectedEnd);
+            }
+            return Err(Error::Syntax(self.position));
+        }
+
+        self.position += s.len();
+        Ok(())
+    }
+
+    fn skip_whitespace(&mut self) {
+        let trimmed = self.rest().trim_start();
+        self.position = self.input.len() - trimmed.len();
+    }
+
+    fn name(&mut self) -> Result<String, Error> {
+        if self.rest().is_empty() {
+            return Err(Error::UnexpectedEnd);
+        }
+
+        let length = self.rest()
+            .find(|c: char|
+                c.is_whitespace() || c == '>' || c == '/' || c == '='
+            )
+            .unwrap_or(self.rest().len());
+
+        if length == 0 {
+            return Err(Error::Syntax(self.position));
+        }
+
+        let name = &self.rest()[.. length];
+        self.position += length;
+
+        Ok(name.to_string())
+    }
+
+    /// Parses an element, starting at its start tag.
+    fn element(&mut self) -> Result<Element, Error> {
+        try!(self.expect("<"));
+
+        let mut element = Element {
+            name      : try!(self.name()),
+            attributes: Vec::new(),
+            children  : Vec::new(),
+            text      : String::new(),
+        };
+
+        loop {
+            self.skip_whitespace();
+
+            if self.rest().starts_with("/>") {
+                self.position += 2;
+                return Ok(element);
+            }
+            if self.rest().starts_with(">") {
+                self.position += 1;
+                break;
+            }
+
+            let name = try!(self.name());
+            self.skip_whitespace();
+            try!(self.expect("="));
+            self.skip_whitespace();
+
+            let quote = match self.rest().chars().next() {
+                Some(quote) if quote == '"' || quote == '\'' => quote,
+                Some(_) => return Err(Error::Syntax(self.position)),
+                None    => return Err(Error::UnexpectedEnd),
+            };
+            self.position += 1;
+
+            let length = try!(
+                self.rest().find(quote).ok_or(Error::UnexpectedEnd)
+            );
+            let value = decode(&self.rest()[.. length]);
+            self.position += length + 1;
+
+            element.attributes.push((name, value));
+        }
+
+        // Content, up to the end tag
+        let mut text = String::new();
+        loop {
+            let length =
+                try!(self.rest().find('<').ok_or(Error::UnexpectedEnd));
+            text.push_str(&decode(&self.rest()[.. length]));
+            self.position += length;
+
+            if self.rest().starts_with("</") {
+                self.position += 2;
+
+                let name = try!(self.name());
+                if name != element.name {
+                    return Err(Error::MismatchedTag(element.name, name));
+                }
+
+                self.skip_whitespace();
+                try!(self.expect(">"));
+                break;
+            }
+            else if self.rest().starts_with("<!--") {
+                try!(self.skip_past("-->"));
+            }
+            else if self.rest().starts_with("<![CDATA[") {
+                self.position += "<![CDATA[".len();
+
+                let length = try!(
+                    self.rest().find("]]>").ok_or(Error::UnexpectedEnd)
+                );
+                text.push_str(&self.rest()[.. length]);
+                self.position += length + "]]>".len();
+            }
+            else if self.rest().starts_with("<?") {
+                try!(self.skip_past("?>"));
+            }
+            else {
+                element.children.push(try!(self.element()));
+            }
+        }
+
+        element.text = text.trim().to_string();
+
+        Ok(element)
+    }
+}
+
+
+/// Replaces the predefined entities with the characters they stand for.
+fn decode(s: &str) -> String {
+    s
+        .replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&apos;", "'")
+        .replace("&amp;", "&")
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        parse,
+        Error,
+    };
+
+
+    #[test]
+    fn parses_elements_attributes_and_text() {
+        let root = parse(
+            "<?xml version=\"1.0\"?>\n\
+             <!-- A comment -->\n\
+             <device schemaVersion='1.1' name=\"a &amp; b\">\n\
+                 <name>SAM3X8E</name>\n\
+                 <empty/>\n\
+                 <text>1 &lt; 2<!-- ignored --><![CDATA[ <&> ]]></text>\n\
+             </device>\n"
+        ).unwrap();
+
+        assert_eq!(root.name, "device");
+        assert_eq!(root.attribute("schemaVersion"), Some("1.1"));
+        assert_eq!(root.attribute("name"), Some("a & b"));
+        assert_eq!(root.attribute("missing"), None);
+
+        assert_eq!(root.children.len(), 3);
+        assert_eq!(root.child_text("name"), Some("SAM3X8E"));
+        assert_eq!(root.child_text("empty"), Some(""));
+        assert_eq!(root.child_text("text"), Some("1 < 2 <&>"));
+    }
+
+    #[test]
+    fn rejects_mismatched_tags() {
+        match parse("<a><b></a></b>") {
+            Err(Error::MismatchedTag(ref start, ref end))
+                if start == "b" && end == "a" => (),
+            result => panic!("Unexpected result: {:?}", result),
+        }
+    }
+
+    #[test]
+    fn rejects_truncated_documents() {
+        for &document in &["<a><b></b>", "<a name=\"b", "<a", "<a><!-- "] {
+            match parse(document) {
+                Err(Error::UnexpectedEnd) => (),
+                result => panic!("Unexpected result: {:?}", result),
+            }
+        }
+    }
+
+    #[test]
+    fn rejects_content_after_the_root_element() {
+        match parse("<a/><b/>") {
+            Err(Error::Syntax(4)) => (),
+            result => panic!("Unexpected result: {:?}", result),
+        }
+    }
+}
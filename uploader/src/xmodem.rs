@@ -0,0 +1,174 @@
+// Implementation of the XMODEM-CRC protocol, as used by the SAM-BA monitor's
+// send file (`S`) and receive file (`R`) commands. See data sheet, section
+// 20.4.3.
+
+
+use std::io::prelude::*;
+
+use result::Result;
+
+
+pub const SOH: u8 = 0x01;
+pub const EOT: u8 = 0x04;
+pub const ACK: u8 = 0x06;
+pub const NAK: u8 = 0x15;
+pub const CAN: u8 = 0x18;
+
+// Sent by the receiver to request a transfer with 16-bit CRC instead of the
+// original 8-bit checksum.
+pub const START: u8 = b'C';
+
+pub const BLOCK_SIZE: usize = 128;
+
+const MAX_RETRIES: usize = 5;
+
+
+/// Sends data to a receiver that has been told to expect it. The data is
+/// padded with zeroes to a multiple of the block size.
+pub fn send<P: Read + Write>(port: &mut P, data: &[u8]) -> Result<()> {
+    try!(wait_for_start(port));
+
+    for (i, chunk) in data.chunks(BLOCK_SIZE).enumerate() {
+        let mut block = [0; BLOCK_SIZE];
+        block[.. chunk.len()].copy_from_slice(chunk);
+
+        // Block numbers start at 1 and wrap around.
+        let number = (i + 1) as u8;
+        let crc    = crc16(&block);
+
+        let mut packet = Vec::with_capacity(BLOCK_SIZE + 5);
+        packet.push(SOH);
+        packet.push(number);
+        packet.push(!number);
+        packet.extend_from_slice(&block);
+        packet.push((crc >> 8) as u8);
+        packet.push(crc as u8);
+
+        let mut retries = 0;
+        loop {
+            try!(port.write_all(&packet));
+
+            match try!(read_byte(port)) {
+                ACK => break,
+                CAN => return Err(Error::Canceled.into()),
+                _   => {
+                    retries += 1;
+                    if retries >= MAX_RETRIES {
+                        return Err(Error::TooManyRetries.into());
+                    }
+                },
+            }
+        }
+    }
+
+    try!(port.write_all(&[EOT]));
+    match try!(read_byte(port)) {
+        ACK => Ok(()),
+        _   => Err(Error::UnexpectedResponse.into()),
+    }
+}
+
+/// Receives data from a sender that has been told to send it. The buffer's
+/// length must match the amount of data the sender has been told to send.
+pub fn receive<P: Read + Write>(port: &mut P, buffer: &mut [u8])
+    -> Result<()>
+{
+    try!(port.write_all(&[START]));
+
+    for (i, chunk) in buffer.chunks_mut(BLOCK_SIZE).enumerate() {
+        let number = (i + 1) as u8;
+
+        let mut retries = 0;
+        loop {
+            let mut packet = [0; BLOCK_SIZE + 5];
+            try!(port.read_exact(&mut packet));
+
+            let block = &packet[3 .. BLOCK_SIZE + 3];
+            let crc   =
+                (packet[BLOCK_SIZE + 3] as u16) << 8
+                | packet[BLOCK_SIZE + 4] as u16;
+
+            let valid =
+                packet[0] == SOH
+                && packet[1] == number
+                && packet[2] == !number
+                && crc16(block) == crc;
+
+            if valid {
+                chunk.copy_from_slice(&block[.. chunk.len()]);
+                try!(port.write_all(&[ACK]));
+                break;
+            }
+
+            retries += 1;
+            if retries >= MAX_RETRIES {
+                try!(port.write_all(&[CAN]));
+                return Err(Error::TooManyRetries.into());
+            }
+
+            try!(port.write_all(&[NAK]));
+        }
+    }
+
+    match try!(read_byte(port)) {
+        EOT => {
+            try!(port.write_all(&[ACK]));
+            Ok(())
+        },
+        _ => Err(Error::UnexpectedResponse.into()),
+    }
+}
+
+
+#[derive(Debug)]
+pub enum Error {
+    /// The receiver didn't request the start of the transfer.
+    NoStart,
+
+    /// The other side canceled the transfer.
+    Canceled,
+
+    /// A block couldn't be transferred, even after several retries.
+    TooManyRetries,
+
+    /// The other side sent something that doesn't fit into the protocol.
+    UnexpectedResponse,
+}
+
+
+fn wait_for_start<P: Read>(port: &mut P) -> Result<()> {
+    // The receiver might send some garbage before it's ready. Let's skip that.
+    for _ in 0 .. MAX_RETRIES {
+        if try!(read_byte(port)) == START {
+            return Ok(());
+        }
+    }
+
+    Err(Error::NoStart.into())
+}
+
+fn read_byte<P: Read>(port: &mut P) -> Result<u8> {
+    let mut byte = [0];
+    try!(port.read_exact(&mut byte));
+    Ok(byte[0])
+}
+
+/// Computes the 16-bit CRC used by XMODEM (polynomial 0x1021, initial value 0).
+pub fn crc16(data: &[u8]) -> u16 {
+    let mut crc: u16 = 0;
+
+    for &byte in data {
+        crc ^= (byte as u16) << 8;
+
+        for _ in 0 .. 8 {
+            crc = if crc & 0x8000 != 0 {
+                crc << 1 ^ 0x1021
+            }
+            else {
+                crc << 1
+            };
+        }
+    }
+
+    crc
+}
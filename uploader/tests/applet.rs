@@ -0,0 +1,122 @@
+extern crate upload;
+
+
+mod common;
+
+
+use upload::applet;
+use upload::applet::Applet;
+use upload::eefc;
+use upload::eefc::Eefc;
+use upload::flash;
+use upload::metadata;
+use upload::metadata::Metadata;
+use upload::result::Error;
+use upload::simulator::Simulator;
+
+
+// The simulator runs the applet's commands from `applet/src/commands.rs`, so
+// these tests exercise the code that runs on the device, and the mailbox
+// layout both sides agree on.
+
+
+#[test]
+fn unknown_commands_are_reported_through_the_mailbox() {
+    let mut simulator = Simulator::new();
+    let mut sam_ba    = common::connect(&mut simulator);
+
+    Applet::load(&mut sam_ba, &[0; 4]).unwrap();
+
+    let mailbox = applet::MAILBOX_ADDR;
+    sam_ba.write_word(mailbox + applet::COMMAND, 99).unwrap();
+    sam_ba.write_word(mailbox + applet::STATUS, applet::STATUS_PENDING)
+        .unwrap();
+    sam_ba.go(applet::CODE_ADDR | 0x1).unwrap();
+
+    let status = sam_ba.read_word(mailbox + applet::STATUS).unwrap();
+    assert_eq!(status, applet::STATUS_UNKNOWN);
+}
+
+#[test]
+fn write_pages_reports_the_page_that_failed() {
+    let mut simulator = Simulator::new();
+    let mut sam_ba    = common::connect(&mut simulator);
+    let     flash     = common::read_flash(&mut sam_ba, false);
+    let     applet    = Applet::load(&mut sam_ba, &[0; 4]).unwrap();
+
+    // The second page is the first one of the second lock region.
+    flash.lock(&mut sam_ba, 0x84000, 1).unwrap();
+
+    let data   = common::pattern(4 * 256, 0);
+    let result = applet.write_pages(
+        &mut sam_ba,
+        &Eefc::eefc_0(),
+        0x83F00,
+        (0x83F00 - flash::BASE_ADDR) / 256,
+        256,
+        &data,
+    );
+
+    match result {
+        Err(Error::Page(0x84000, ref error)) =>
+            match **error {
+                Error::Eefc(eefc::Error::Lock) => (),
+                ref error => panic!("Unexpected error: {:?}", error),
+            },
+        result =>
+            panic!("Unexpected result: {:?}", result),
+    }
+
+    // The page before the locked region was written.
+    let mut page = vec![0; 256];
+    sam_ba.receive_file(0x83F00, &mut page).unwrap();
+    assert_eq!(page, &data[.. 256]);
+}
+
+#[test]
+fn crc32_pages_matches_the_uploaders_crc32() {
+    let mut simulator = Simulator::new();
+    let mut sam_ba    = common::connect(&mut simulator);
+    let     flash     = common::read_flash(&mut sam_ba, false);
+
+    let data  = common::pattern(8 * 256, 3);
+    let image = common::image(flash::BASE_ADDR, data.clone());
+    flash.write(&mut sam_ba, &image, |_| ()).unwrap();
+
+    let applet = Applet::load(&mut sam_ba, &[0; 4]).unwrap();
+    let crcs   = applet.crc32_pages(&mut sam_ba, flash::BASE_ADDR, 8, 256)
+        .unwrap();
+
+    let expected: Vec<u32> = data.chunks(256).map(applet::crc32).collect();
+    assert_eq!(crcs, expected);
+}
+
+#[test]
+fn metadata_checksum_is_the_same_with_the_applet() {
+    let mut simulator = Simulator::new();
+    let mut sam_ba    = common::connect(&mut simulator);
+    let     flash     = common::read_flash(&mut sam_ba, false);
+
+    // A metadata block at 0x100, with the image length and a CRC32 that
+    // doesn't match. The rest of the block doesn't matter.
+    let mut data = common::pattern(4 * 1024, 5);
+    data[0x100 .. 0x108].copy_from_slice(metadata::MAGIC);
+    data[0x100 + 76 .. 0x100 + 80].copy_from_slice(&[0x00, 0x10, 0, 0]);
+    data[0x100 + 80 .. 0x100 + 84].copy_from_slice(&[1, 2, 3, 4]);
+
+    let image = common::image(flash::BASE_ADDR, data.clone());
+    flash.write(&mut sam_ba, &image, |_| ()).unwrap();
+
+    let metadata = Metadata::find(&data, flash::BASE_ADDR).unwrap();
+    let expected = metadata.checksum(&data).unwrap();
+
+    let applet = Applet::load(&mut sam_ba, &[0; 4]).unwrap();
+    assert_eq!(
+        metadata.read_checksum(&mut sam_ba, Some(&applet)).unwrap(),
+        Some(expected),
+    );
+    assert_eq!(
+        metadata.read_checksum(&mut sam_ba, None).unwrap(),
+        Some(expected),
+    );
+}
@@ -0,0 +1,58 @@
+// Helpers for the tests, which run the library against the simulated SAM-BA
+// monitor (see `simulator` module). Not every test uses all of them.
+#![allow(dead_code)]
+
+
+use upload::applet::Applet;
+use upload::flash::Flash;
+use upload::image::{
+    Image,
+    Segment,
+};
+use upload::sam_ba;
+use upload::simulator::Simulator;
+
+
+pub type SamBa<'r> = sam_ba::SamBa<&'r mut Simulator>;
+
+
+/// Connects to the simulator, like the uploader connects to a device.
+pub fn connect<'r>(simulator: &'r mut Simulator) -> SamBa<'r> {
+    let mut sam_ba = sam_ba::SamBa::new(simulator);
+    sam_ba.set_normal_mode().expect("Failed to connect to simulator");
+    sam_ba
+}
+
+/// Reads the flash descriptors and, if `applet` is set, loads the applet.
+/// The simulator runs the applet's commands (see `applet::commands`) itself,
+/// so the code that's loaded doesn't matter.
+pub fn read_flash(sam_ba: &mut SamBa, applet: bool) -> Flash {
+    let mut flash = Flash::read(sam_ba).expect("Failed to read flash");
+
+    if applet {
+        flash.use_applet(
+            Applet::load(sam_ba, &[0; 4]).expect("Failed to load applet")
+        );
+    }
+
+    flash
+}
+
+/// Returns an image with a single segment at the given address.
+pub fn image(address: u32, data: Vec<u8>) -> Image {
+    Image::new(vec![
+        Segment {
+            address: address,
+            data   : data,
+        },
+    ])
+    .expect("Failed to create image")
+}
+
+/// Returns data that differs from page to page, so pages that end up in the
+/// wrong place are noticed.
+pub fn pattern(length: usize, seed: u8) -> Vec<u8> {
+    (0 .. length)
+        .map(|i| (i as u8).wrapping_mul(7) ^ (i >> 8) as u8 ^ seed)
+        .collect()
+}
@@ -0,0 +1,131 @@
+extern crate upload;
+
+
+mod common;
+
+
+use upload::flash;
+use upload::result::Error;
+use upload::simulator::Simulator;
+
+
+#[test]
+fn write_keeps_the_rest_of_partial_pages() {
+    for &applet in &[false, true] {
+        let mut simulator = Simulator::new();
+
+        let data = common::pattern(300 * 1024, 0);
+        {
+            let mut sam_ba = common::connect(&mut simulator);
+            let     flash  = common::read_flash(&mut sam_ba, applet);
+
+            let image = common::image(flash::BASE_ADDR, data.clone());
+            flash.write(&mut sam_ba, &image, |_| ()).unwrap();
+
+            let patch = common::image(0x80010, vec![1, 2, 3, 4]);
+            flash.write(&mut sam_ba, &patch, |_| ()).unwrap();
+        }
+
+        let mut expected = data[.. 0x20].to_vec();
+        expected[0x10 .. 0x14].copy_from_slice(&[1, 2, 3, 4]);
+
+        assert_eq!(&simulator.flash()[.. 0x20], &expected[..]);
+        assert_eq!(&simulator.flash()[0x20 .. data.len()], &data[0x20 ..]);
+    }
+}
+
+#[test]
+fn write_rejects_segments_outside_flash() {
+    let mut simulator = Simulator::new();
+    let mut sam_ba    = common::connect(&mut simulator);
+    let     flash     = common::read_flash(&mut sam_ba, false);
+
+    let image = common::image(0x20070000, vec![0; 4]);
+
+    match flash.write(&mut sam_ba, &image, |_| ()) {
+        Err(Error::Flash(flash::CheckError::OutsideFlash(0x20070000, 4))) =>
+            (),
+        result =>
+            panic!("Unexpected result: {:?}", result.map(|_| ())),
+    }
+}
+
+#[test]
+fn erase_keeps_locked_regions() {
+    for &applet in &[false, true] {
+        let mut simulator = Simulator::new();
+
+        let data = common::pattern(64 * 1024, 0);
+        {
+            let mut sam_ba = common::connect(&mut simulator);
+            let     flash  = common::read_flash(&mut sam_ba, applet);
+
+            let image = common::image(flash::BASE_ADDR, data.clone());
+            flash.write(&mut sam_ba, &image, |_| ()).unwrap();
+
+            flash.lock(&mut sam_ba, flash::BASE_ADDR + 0x4000, 1).unwrap();
+            flash.erase(&mut sam_ba).unwrap();
+        }
+
+        let flash = simulator.flash();
+        assert!(flash[.. 0x4000].iter().all(|&byte| byte == 0xff));
+        assert_eq!(&flash[0x4000 .. 0x8000], &data[0x4000 .. 0x8000]);
+        assert!(flash[0x8000 ..].iter().all(|&byte| byte == 0xff));
+        assert_eq!(simulator.lock_bits(0), 0x2);
+    }
+}
+
+#[test]
+fn write_and_verify_survive_a_lost_byte() {
+    // Lose a byte at every point of the session in turn, including while
+    // connecting and reading the flash descriptors.
+    for &applet in &[false, true] {
+        for n in 1 .. 50 {
+            let mut simulator = Simulator::new();
+            simulator.lose_reply(n);
+
+            let data = common::pattern(2 * 1024, n as u8);
+            write_and_verify(&mut simulator, applet, &data);
+
+            assert_eq!(&simulator.flash()[.. data.len()], &data[..]);
+        }
+    }
+}
+
+#[test]
+fn write_and_verify_survive_stray_bytes() {
+    for &applet in &[false, true] {
+        let mut simulator = Simulator::new();
+        simulator.inject(b"\n\r>stray output");
+
+        let data = common::pattern(2 * 1024, 0);
+        {
+            let mut sam_ba = common::connect(&mut simulator);
+            let     flash  = common::read_flash(&mut sam_ba, applet);
+
+            let image = common::image(flash::BASE_ADDR, data.clone());
+            flash.write(&mut sam_ba, &image, |_| ()).unwrap();
+        }
+
+        // Garbage in the middle of a session, right before the reply to the
+        // first command of the verification.
+        simulator.inject(b"garbage");
+        write_and_verify(&mut simulator, applet, &data);
+
+        assert_eq!(&simulator.flash()[.. data.len()], &data[..]);
+    }
+}
+
+
+/// Writes the data to the start of the flash memory, then makes sure that it
+/// verifies.
+fn write_and_verify(simulator: &mut Simulator, applet: bool, data: &[u8]) {
+    let mut sam_ba = common::connect(simulator);
+    let     flash  = common::read_flash(&mut sam_ba, applet);
+
+    let image = common::image(flash::BASE_ADDR, data.to_vec());
+    flash.write(&mut sam_ba, &image, |_| ()).unwrap();
+
+    let verification = flash.verify(&mut sam_ba, &image, |_| ()).unwrap();
+    assert_eq!(verification.first_mismatch, None);
+}
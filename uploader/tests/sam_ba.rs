@@ -0,0 +1,166 @@
+#[macro_use]
+extern crate upload;
+
+
+mod common;
+
+
+use std::cell::Cell;
+use std::io;
+use std::io::prelude::*;
+use std::rc::Rc;
+
+use upload::result::Error;
+use upload::sam_ba;
+use upload::sam_ba::SamBa;
+use upload::simulator::Simulator;
+
+
+#[test]
+fn send_and_receive_file_without_xmodem() {
+    let mut simulator = Simulator::new();
+    simulator.set_raw_transfers(true);
+
+    let mut sam_ba = common::connect(&mut simulator);
+    sam_ba.set_raw_transfers(true);
+
+    let data = common::pattern(1000, 0);
+    sam_ba.send_file(0x20072000, &data).unwrap();
+
+    let mut buffer = vec![0; data.len()];
+    sam_ba.receive_file(0x20072000, &mut buffer).unwrap();
+
+    assert_eq!(buffer, data);
+}
+
+#[test]
+fn send_file_transfers_fewer_bytes_than_writing_words() {
+    let data = common::pattern(4096, 0);
+
+    let mut simulator = Simulator::new();
+    let     traffic   = Rc::new(Cell::new(0));
+
+    let words = {
+        let mut sam_ba = SamBa::new(Counting {
+            inner  : &mut simulator,
+            traffic: traffic.clone(),
+        });
+        sam_ba.set_normal_mode().unwrap();
+
+        let start = traffic.get();
+        for (i, word) in data.chunks(4).enumerate() {
+            let word = word
+                .iter()
+                .rev()
+                .fold(0, |word, &byte| word << 8 | byte as u32);
+            sam_ba.write_word(0x20072000 + i as u32 * 4, word).unwrap();
+        }
+        traffic.get() - start
+    };
+
+    let file = {
+        let mut sam_ba = SamBa::new(Counting {
+            inner  : &mut simulator,
+            traffic: traffic.clone(),
+        });
+
+        let start = traffic.get();
+        sam_ba.send_file(0x20074000, &data).unwrap();
+        traffic.get() - start
+    };
+
+    let mut sam_ba = common::connect(&mut simulator);
+    for &address in &[0x20072000, 0x20074000] {
+        let mut buffer = vec![0; data.len()];
+        sam_ba.receive_file(address, &mut buffer).unwrap();
+        assert_eq!(buffer, data);
+    }
+
+    assert!(file * 4 < words, "send_file: {}, words: {}", file, words);
+}
+
+#[test]
+fn ranges_may_end_at_the_end_of_the_address_space() {
+    let mut simulator = Simulator::new();
+    let mut sam_ba    = common::connect(&mut simulator);
+
+    let mut buffer = [0xaa; 4];
+    sam_ba.read_memory(0xFFFFFFFC, &mut buffer).unwrap();
+    assert_eq!(buffer, [0; 4]);
+
+    let mut chunks = Vec::new();
+    sam_ba
+        .dump(0xFFFFFF00, 0x100, |address, chunk| {
+            chunks.push((address, chunk.len()));
+            Ok(())
+        })
+        .unwrap();
+    assert_eq!(chunks, [(0xFFFFFF00, 0x100)]);
+
+    match sam_ba.read_memory(0xFFFFFFFD, &mut buffer) {
+        Err(Error::SamBa(sam_ba::Error::OutOfRange(0xFFFFFFFD, 4))) =>
+            (),
+        result =>
+            panic!("Unexpected result: {:?}", result),
+    }
+}
+
+#[test]
+fn a_silent_port_is_reported_as_not_responding() {
+    let mut sam_ba = SamBa::new(Silent);
+
+    match sam_ba.set_normal_mode() {
+        Err(Error::SamBa(sam_ba::Error::NotResponding)) =>
+            (),
+        result =>
+            panic!("Unexpected result: {:?}", result),
+    }
+}
+
+
+/// Counts the bytes that go through a transport in either direction.
+struct Counting<T> {
+    inner  : T,
+    traffic: Rc<Cell<usize>>,
+}
+
+impl<T: Read> Read for Counting<T> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = try!(self.inner.read(buf));
+        self.traffic.set(self.traffic.get() + n);
+        Ok(n)
+    }
+}
+
+impl<T: Write> Write for Counting<T> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = try!(self.inner.write(buf));
+        self.traffic.set(self.traffic.get() + n);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+
+/// Accepts anything written to it, but never replies, like a serial port
+/// without anything on the other end.
+struct Silent;
+
+impl Read for Silent {
+    fn read(&mut self, _: &mut [u8]) -> io::Result<usize> {
+        Err(io::Error::new(io::ErrorKind::TimedOut, "timed out"))
+    }
+}
+
+impl Write for Silent {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
@@ -0,0 +1,171 @@
+extern crate upload;
+
+
+mod common;
+
+
+use upload::eefc::{
+    ClearGpnvmBit,
+    Eefc,
+    GetGpnvmBit,
+    GpnvmNumber,
+    SetGpnvmBit,
+};
+use upload::flash;
+use upload::result::Error;
+use upload::simulator::Simulator;
+
+
+// 1200 pages starting at the base address reach well into the second bank,
+// which starts at 0xC0000.
+const PAGES: usize = 1200;
+
+
+#[test]
+fn write_verify_and_diff_across_banks() {
+    for &applet in &[false, true] {
+        let mut simulator = Simulator::new();
+
+        let data = common::pattern(PAGES * 256, 0);
+        {
+            let mut sam_ba = common::connect(&mut simulator);
+            let     flash  = common::read_flash(&mut sam_ba, applet);
+
+            let image = common::image(flash::BASE_ADDR, data.clone());
+
+            let pages = flash.write(&mut sam_ba, &image, |_| ()).unwrap();
+            assert_eq!(pages, PAGES as u32);
+
+            let verification = flash.verify(&mut sam_ba, &image, |_| ())
+                .unwrap();
+            assert_eq!(verification.first_mismatch, None);
+            assert_eq!(verification.mismatched_pages, 0);
+
+            let written = flash.write_changed(&mut sam_ba, &image, |_| ())
+                .unwrap();
+            assert_eq!(written.pages_written, 0);
+            assert_eq!(written.pages_skipped, PAGES as u32);
+
+            // Change a single byte in the second bank
+            let mut changed = data.clone();
+            changed[0x40000 + 0x1234] ^= 0xff;
+            let changed = common::image(flash::BASE_ADDR, changed);
+
+            let verification = flash.verify(&mut sam_ba, &changed, |_| ())
+                .unwrap();
+            assert_eq!(verification.first_mismatch, Some(0xC1234));
+            assert_eq!(verification.mismatched_pages, 1);
+
+            let written = flash.write_changed(&mut sam_ba, &changed, |_| ())
+                .unwrap();
+            assert_eq!(written.pages_written, 1);
+            assert_eq!(written.pages_skipped, PAGES as u32 - 1);
+        }
+
+        let flash = simulator.flash();
+        assert_eq!(&flash[.. 0x41234], &data[.. 0x41234]);
+        assert_eq!(flash[0x41234], data[0x41234] ^ 0xff);
+        assert_eq!(&flash[0x41235 .. data.len()], &data[0x41235 ..]);
+    }
+}
+
+#[test]
+fn lock_and_unlock() {
+    let mut simulator = Simulator::new();
+    {
+        let mut sam_ba = common::connect(&mut simulator);
+        let     flash  = common::read_flash(&mut sam_ba, false);
+
+        // Regions are 16 KiB, so this touches the last region of the first
+        // bank and the first region of the second.
+        let affected = flash.lock(&mut sam_ba, 0xBF000, 0x2000).unwrap();
+        assert_eq!(affected, 2);
+
+        let locked: Vec<u32> = flash.lock_regions(&mut sam_ba)
+            .unwrap()
+            .iter()
+            .filter(|region| region.locked)
+            .map(|region| region.address)
+            .collect();
+        assert_eq!(locked, vec![0xBC000, 0xC0000]);
+
+        let image = common::image(0xC2000, vec![0; 256]);
+        match flash.check_locks(&mut sam_ba, &image) {
+            Err(Error::Locked(0xC0000)) => (),
+            result => panic!("Unexpected result: {:?}", result),
+        }
+    }
+
+    assert_eq!(simulator.lock_bits(0), 0x8000);
+    assert_eq!(simulator.lock_bits(1), 0x0001);
+
+    {
+        let mut sam_ba = common::connect(&mut simulator);
+        let     flash  = common::read_flash(&mut sam_ba, false);
+
+        let affected = flash.unlock(&mut sam_ba, 0xC0000, 1).unwrap();
+        assert_eq!(affected, 1);
+    }
+
+    assert_eq!(simulator.lock_bits(0), 0x8000);
+    assert_eq!(simulator.lock_bits(1), 0);
+}
+
+#[test]
+fn set_and_clear_gpnvm_bits() {
+    let mut simulator = Simulator::new();
+    {
+        let mut sam_ba = common::connect(&mut simulator);
+        let     eefc   = Eefc::eefc_0();
+
+        eefc.execute_command::<SetGpnvmBit, _, _>(
+            &mut sam_ba, GpnvmNumber::BootModeSelection,
+        ).unwrap();
+        eefc.execute_command::<SetGpnvmBit, _, _>(
+            &mut sam_ba, GpnvmNumber::FlashSelection,
+        ).unwrap();
+        eefc.execute_command::<ClearGpnvmBit, _, _>(
+            &mut sam_ba, GpnvmNumber::FlashSelection,
+        ).unwrap();
+
+        let bits = eefc.execute_command::<GetGpnvmBit, _, _>(&mut sam_ba, ())
+            .unwrap();
+        assert_eq!(bits, 0b010);
+    }
+
+    assert_eq!(simulator.gpnvm_bits(), 0b010);
+}
+
+#[test]
+fn read_unique_id() {
+    let mut simulator = Simulator::new();
+
+    let mut sam_ba = common::connect(&mut simulator);
+    let     flash  = common::read_flash(&mut sam_ba, false);
+
+    let data  = common::pattern(256, 0);
+    let image = common::image(flash::BASE_ADDR, data.clone());
+    flash.write(&mut sam_ba, &image, |_| ()).unwrap();
+
+    let unique_id = Eefc::eefc_0()
+        .read_unique_id(&mut sam_ba, flash::BASE_ADDR)
+        .unwrap();
+    assert_eq!(&unique_id, b"SIMULATED SAM3X8");
+
+    // Once the unique ID has been read, the flash memory is back.
+    let mut buffer = vec![0; data.len()];
+    sam_ba.receive_file(flash::BASE_ADDR, &mut buffer).unwrap();
+    assert_eq!(buffer, data);
+}
+
+#[test]
+fn reads_wrap_around_the_end_of_the_address_space() {
+    let mut simulator = Simulator::new();
+    let mut sam_ba    = common::connect(&mut simulator);
+
+    // Like the monitor, the simulator doesn't check the range. It must not
+    // crash, either.
+    let mut buffer = vec![0xaa; 0x20];
+    sam_ba.receive_file(0xFFFFFFF0, &mut buffer).unwrap();
+    sam_ba.read_word(0xFFFFFFFE).unwrap();
+}